@@ -0,0 +1,43 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use okc_agents::deflate::{Deflater, Inflater};
+
+// The longest match, the most a single symbol can add beyond the limit.
+const MAX_MATCH: usize = 258;
+
+/// Feeds `data` in pieces of `piece` bytes and drains the output at most `max_len` bytes at a time, as the
+/// compressed transfer mode does.
+fn inflate(data: &[u8], piece: usize, max_len: usize) -> Option<Vec<u8>> {
+	let mut inflater = Inflater::new();
+	let mut out = Vec::new();
+	for piece in data.chunks(piece) {
+		let mut data = piece;
+		loop {
+			let mut buf = Vec::new();
+			inflater.decompress(data, &mut buf, max_len).ok()?;
+			assert!(buf.len() < max_len + MAX_MATCH, "{} bytes at once", buf.len());
+			data = &[];
+			if buf.is_empty() {
+				break;
+			}
+			out.extend_from_slice(&buf);
+		}
+	}
+	Some(out).filter(|_| inflater.is_finished())
+}
+
+fuzz_target!(|data: &[u8]| {
+	let (piece, max_len, data) = match data {
+		[piece, max_len, rest @ ..] => (*piece as usize + 1, *max_len as usize * 256 + 1, rest),
+		_ => return,
+	};
+	let _ = inflate(data, piece, max_len);
+	// Whatever the encoder makes of the input has to decode to it again.
+	let mut deflater = Deflater::new();
+	let mut compressed = Vec::new();
+	for chunk in data.chunks(piece * 97) {
+		deflater.compress(chunk, &mut compressed);
+	}
+	deflater.finish(&mut compressed);
+	assert!(inflate(&compressed, piece, max_len).as_deref() == Some(data));
+});
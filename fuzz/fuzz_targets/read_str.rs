@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use okc_agents::proto::{read_bytes, read_str};
+
+const MAX_LEN: usize = 1024;
+
+fuzz_target!(|data: &[u8]| {
+	let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+	rt.block_on(async {
+		let mut rx = data;
+		while let Ok(frame) = read_bytes(&mut rx, MAX_LEN).await {
+			assert!(frame.len() <= MAX_LEN);
+		}
+		let _ = read_str(&mut &data[..]).await;
+	});
+});
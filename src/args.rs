@@ -0,0 +1,221 @@
+//! Translation of GnuPG arguments the app doesn't understand, for wrappers written against a specific gpg.
+
+use std::str::FromStr;
+
+/// Which GnuPG's command line conventions the arguments follow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+	/// Classic GnuPG 1.x, whose agent and v3 options are gone in 2.x and some of whose spellings changed.
+	Gpg1,
+	/// GnuPG 2.x, whose options for controlling gpg-agent make no sense with the app handling keys itself.
+	Gpg2,
+}
+
+impl FromStr for Profile {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, ()> {
+		match s {
+			"gpg1" => Ok(Self::Gpg1),
+			"gpg2" => Ok(Self::Gpg2),
+			_ => Err(()),
+		}
+	}
+}
+
+/// How an option is handled. Options taking a value also drop the following argument unless written as
+/// `--option=value`.
+enum Rule {
+	Drop,
+	DropWithValue,
+	Rename(&'static str),
+}
+
+const GPG1_RULES: &[(&str, Rule)] = &[
+	("--use-agent", Rule::Drop),
+	("--no-use-agent", Rule::Drop),
+	("--force-v3-sigs", Rule::Drop),
+	("--no-force-v3-sigs", Rule::Drop),
+	("--force-v4-certs", Rule::Drop),
+	("--no-force-v4-certs", Rule::Drop),
+	("--clearsign", Rule::Rename("--clear-sign")),
+];
+
+const GPG2_RULES: &[(&str, Rule)] = &[
+	("--no-autostart", Rule::Drop),
+	("--pinentry-mode", Rule::DropWithValue),
+	("--agent-program", Rule::DropWithValue),
+];
+
+/// An argument that was dropped or rewritten by [`translate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+	Dropped(Vec<String>),
+	Renamed(String, String),
+}
+
+/// Applies the rules of `profile` to `args`. Everything after `--` is left alone.
+pub fn translate(args: &[String], profile: Profile) -> (Vec<String>, Vec<Change>) {
+	let rules = match profile {
+		Profile::Gpg1 => GPG1_RULES,
+		Profile::Gpg2 => GPG2_RULES,
+	};
+	let mut translated = Vec::with_capacity(args.len());
+	let mut changes = Vec::new();
+	let mut iter = args.iter();
+	while let Some(arg) = iter.next() {
+		if arg == "--" {
+			translated.push(arg.clone());
+			translated.extend(iter.cloned());
+			break;
+		}
+		let (name, value) = match arg.split_once('=') {
+			Some((name, value)) => (name, Some(value)),
+			None => (&arg[..], None),
+		};
+		match rules.iter().find(|(option, _)| *option == name).map(|(_, rule)| rule) {
+			Some(Rule::Drop) => changes.push(Change::Dropped(vec![arg.clone()])),
+			Some(Rule::DropWithValue) => {
+				let mut dropped = vec![arg.clone()];
+				if value.is_none() {
+					dropped.extend(iter.next().cloned());
+				}
+				changes.push(Change::Dropped(dropped));
+			}
+			Some(Rule::Rename(new_name)) => {
+				let renamed = match value {
+					Some(value) => format!("{}={}", new_name, value),
+					None => new_name.to_string(),
+				};
+				changes.push(Change::Renamed(arg.clone(), renamed.clone()));
+				translated.push(renamed);
+			}
+			None => translated.push(arg.clone()),
+		}
+	}
+	(translated, changes)
+}
+
+/// The option passing an additional intent extra to the app, as `--okc-extra key[:type]=value`.
+pub const EXTRA_OPTION: &str = "--okc-extra";
+/// The prefix of the app's extras, added to keys not containing a dot.
+pub const EXTRA_PREFIX: &str = "org.ddosolitary.okcagent.extra.";
+
+/// An additional intent extra given with [`EXTRA_OPTION`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Extra {
+	pub key: String,
+	/// The `am` option for the type of the value, such as `--ei`.
+	pub flag: &'static str,
+	pub value: String,
+}
+
+impl FromStr for Extra {
+	type Err = String;
+
+	/// Parses `key[:type]=value`, where the type is one of `s`, `i`, `l`, `f` and `b`. Without one, values
+	/// looking like a bool or an int are sent as such and everything else as a string.
+	fn from_str(s: &str) -> Result<Self, String> {
+		let (key, value) = s.split_once('=').ok_or_else(|| format!("expected key=value, got {:?}", s))?;
+		let (key, flag) = match key.split_once(':') {
+			Some((key, kind)) => {
+				let flag = match kind {
+					"s" => "--es",
+					"i" => "--ei",
+					"l" => "--el",
+					"f" => "--ef",
+					"b" => "--ez",
+					_ => return Err(format!("unknown type {:?} for extra {:?}", kind, key)),
+				};
+				(key, flag)
+			}
+			None if value == "true" || value == "false" => (key, "--ez"),
+			None if value.parse::<i32>().is_ok() => (key, "--ei"),
+			None => (key, "--es"),
+		};
+		if key.is_empty() {
+			return Err(format!("missing key in {:?}", s));
+		}
+		let key = if key.contains('.') { key.to_owned() } else { format!("{}{}", EXTRA_PREFIX, key) };
+		Ok(Self { key, flag, value: value.to_owned() })
+	}
+}
+
+/// Removes the [`EXTRA_OPTION`]s at the start of `args`, before the arguments for GnuPG.
+pub fn take_extras(args: &mut Vec<String>) -> Result<Vec<Extra>, String> {
+	let mut extras = Vec::new();
+	let mut taken = 0;
+	while let Some(arg) = args.get(taken) {
+		let value = if arg == EXTRA_OPTION {
+			taken += 1;
+			args.get(taken).ok_or_else(|| format!("{} requires a value", EXTRA_OPTION))?
+		} else if let Some(value) = arg.strip_prefix(EXTRA_OPTION).and_then(|rest| rest.strip_prefix('=')) {
+			value
+		} else {
+			break;
+		};
+		extras.push(value.parse()?);
+		taken += 1;
+	}
+	args.drain(..taken);
+	Ok(extras)
+}
+
+/// The option running the operations listed in a file concurrently, as `--okc-batch <file>`.
+pub const BATCH_OPTION: &str = "--okc-batch";
+
+/// Splits a line into words at whitespace. Words may be quoted with `'` or `"`, and `\` escapes the next
+/// character outside of single quotes.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+	let mut words = Vec::new();
+	let mut word = None::<String>;
+	let mut quote = None;
+	let mut chars = line.chars();
+	while let Some(c) = chars.next() {
+		match (quote, c) {
+			(Some(q), c) if c == q => quote = None,
+			(Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+			(_, '\\') => {
+				let escaped = chars.next().ok_or_else(|| "trailing backslash".to_owned())?;
+				word.get_or_insert_with(String::new).push(escaped);
+			}
+			(None, '\'') | (None, '"') => {
+				quote = Some(c);
+				word.get_or_insert_with(String::new);
+			}
+			(None, c) if c.is_whitespace() => words.extend(word.take()),
+			(_, c) => word.get_or_insert_with(String::new).push(c),
+		}
+	}
+	if quote.is_some() {
+		return Err("unterminated quote".to_owned());
+	}
+	words.extend(word);
+	Ok(words)
+}
+
+/// Parses a batch file: the GnuPG arguments of one operation per line, split like a shell would without
+/// any expansion. Empty lines and lines starting with `#` are skipped.
+pub fn parse_batch(spec: &str) -> Result<Vec<Vec<String>>, String> {
+	spec.lines().enumerate()
+		.filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+		.map(|(i, line)| split_words(line).map_err(|e| format!("line {}: {}", i + 1, e)))
+		.collect()
+}
+
+/// Replaces the argument at `index` with `-` and returns it, for okc-gpg to stream that file as stdin. The
+/// index starts at 1, negative ones count from the end so that -1 is the last argument.
+pub fn take_input_arg(args: &[String], index: isize) -> Result<(Vec<String>, String), String> {
+	let position = match index {
+		0 => return Err("argument indices start at 1".to_owned()),
+		i if i > 0 => (i - 1) as usize,
+		i => args.len().checked_sub(i.unsigned_abs()).ok_or_else(|| format!("there is no argument {}", index))?,
+	};
+	let mut args = args.to_vec();
+	let arg = args.get_mut(position).ok_or_else(|| format!("there is no argument {}", index))?;
+	if arg.starts_with('-') {
+		return Err(format!("argument {} is {:?}, not a file", index, arg));
+	}
+	let path = std::mem::replace(arg, "-".to_owned());
+	Ok((args, path))
+}
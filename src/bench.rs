@@ -0,0 +1,163 @@
+//! `okc-gpg --okc-bench`: measures the throughput of the copy loops for a few chunk sizes, using a mock
+//! app on the loopback interface instead of a broadcast.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use futures_util::future::BoxFuture;
+use slog::Logger;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use crate::gpg::{self, Broadcaster, Limits, Options};
+use crate::proto::{OP_CONTROL, OP_INPUT, OP_OUTPUT, read_bytes};
+use crate::utils::OkcError;
+
+/// The option selecting the benchmark, which must be the first argument.
+pub const BENCH_OPTION: &str = "--okc-bench";
+// How many bytes to send in each direction, 32 MiB by default.
+pub const BENCH_BYTES_ENV: &str = "OKC_BENCH_BYTES";
+const DEFAULT_BENCH_BYTES: u64 = 32 << 20;
+const CHUNK_SIZES: &[usize] = &[512, 4096, 16384, u16::MAX as usize];
+// The workload for comparing streamed and buffered small files, like signing many git objects.
+const SMALL_FILE_LEN: u64 = 1024;
+const SMALL_FILE_OPERATIONS: u32 = 200;
+const SMALL_FILE_SIZE: u64 = 64 << 10;
+
+/// How long the mock app took to read the input and to write the output.
+#[derive(Clone, Copy, Debug)]
+struct Timings {
+	input: Duration,
+	output: Duration,
+}
+
+/// Stands in for the app: reads the whole input file, then writes as much to the output file.
+struct BenchApp {
+	input: String,
+	output: String,
+	len: u64,
+	timings: Arc<Mutex<Option<Result<Timings, String>>>>,
+}
+
+async fn connect(port: u16, op: u8, path: &str) -> Result<TcpStream, OkcError> {
+	let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+	stream.write_u8(op).await?;
+	stream.write_u16(path.len() as u16).await?;
+	stream.write_all(path.as_bytes()).await?;
+	Ok(stream)
+}
+
+async fn play(port: u16, input: String, output: String, len: u64) -> Result<Timings, OkcError> {
+	let start = Instant::now();
+	let mut stream = connect(port, OP_INPUT, &input).await?;
+	while !read_bytes(&mut stream, u16::MAX as usize).await?.is_empty() {}
+	drop(stream);
+	let input_time = start.elapsed();
+
+	let start = Instant::now();
+	let mut stream = connect(port, OP_OUTPUT, &output).await?;
+	let frame = vec![0x5au8; u16::MAX as usize];
+	let mut left = len;
+	while left > 0 {
+		let frame_len = left.min(frame.len() as u64) as usize;
+		stream.write_u16(frame_len as u16).await?;
+		stream.write_all(&frame[..frame_len]).await?;
+		left -= frame_len as u64;
+	}
+	stream.write_u16(0).await?;
+	stream.shutdown().await?;
+	tokio::io::copy(&mut stream, &mut tokio::io::sink()).await?;
+	let output_time = start.elapsed();
+
+	// The empty string ends the control messages right away.
+	let mut control = connect(port, OP_CONTROL, "").await?;
+	control.write_u8(0).await?;
+	Ok(Timings { input: input_time, output: output_time })
+}
+
+impl Broadcaster for BenchApp {
+	fn send<'a>(&'a self, port: u16, _args: &'a [String]) -> BoxFuture<'a, Result<(), OkcError>> {
+		let (input, output, len, timings) = (self.input.clone(), self.output.clone(), self.len, self.timings.clone());
+		tokio::spawn(async move {
+			let res = play(port, input, output, len).await.map_err(|e| e.to_string());
+			*timings.lock().unwrap() = Some(res);
+		});
+		Box::pin(async { Ok(()) })
+	}
+}
+
+fn throughput(len: u64, time: Duration) -> f64 {
+	len as f64 / (1 << 20) as f64 / time.as_secs_f64().max(1e-9)
+}
+
+fn temp_path(name: &str) -> PathBuf {
+	std::env::temp_dir().join(format!("okc-bench-{}-{}", std::process::id(), name))
+}
+
+/// Runs the benchmark and prints the results to stdout.
+pub async fn run(logger: Logger) -> Result<(), OkcError> {
+	let len = match std::env::var(BENCH_BYTES_ENV) {
+		Ok(s) if !s.is_empty() => s.parse::<u64>()
+			.map_err(|e| OkcError::Other(format!("invalid value for {}: {}", BENCH_BYTES_ENV, e)))?,
+		_ => DEFAULT_BENCH_BYTES,
+	};
+	let (input, output) = (temp_path("input"), temp_path("output"));
+	let res = match run_sizes(len, &input, &output, &logger).await {
+		Ok(()) => run_small_files(&input, &output, &logger).await,
+		Err(e) => Err(e),
+	};
+	let _ = std::fs::remove_file(&input);
+	let _ = std::fs::remove_file(&output);
+	res
+}
+
+fn path_str(path: &Path) -> Result<String, OkcError> {
+	path.to_str().map(str::to_owned).ok_or_else(|| OkcError::Other("the temporary directory is not UTF-8".to_owned()))
+}
+
+/// Runs one operation and returns how long the mock app took.
+async fn run_once(input: &Path, output: &Path, len: u64, limits: Limits, logger: &Logger) -> Result<Timings, OkcError> {
+	let timings = Arc::new(Mutex::new(None));
+	let app = BenchApp { input: path_str(input)?, output: path_str(output)?, len, timings: timings.clone() };
+	let options = Options { limits, ..Options::default() };
+	gpg::run(&app, &options, &[], logger.clone()).await?;
+	let res = timings.lock().unwrap().take();
+	res.unwrap_or_else(|| Err("no result".to_owned()))
+		.map_err(|e| OkcError::Other(format!("the benchmark client failed: {}", e)))
+}
+
+async fn run_sizes(len: u64, input: &Path, output: &Path, logger: &Logger) -> Result<(), OkcError> {
+	std::fs::File::create(input)?.set_len(len)?;
+	println!("transferring {} bytes in each direction", len);
+	let mut best = (0.0, 0);
+	for &chunk_size in CHUNK_SIZES {
+		let timings = run_once(input, output, len, Limits { chunk_size, ..Limits::default() }, logger).await?;
+		let (input_rate, output_rate) = (throughput(len, timings.input), throughput(len, timings.output));
+		println!("chunk size {:>5}: input {:>8.1} MiB/s, output {:>8.1} MiB/s", chunk_size, input_rate, output_rate);
+		// Both directions count, so rank by the slower one.
+		let rate = input_rate.min(output_rate);
+		if rate > best.0 {
+			best = (rate, chunk_size);
+		}
+	}
+	if best.1 == Limits::default().chunk_size {
+		println!("recommendation: keep the default chunk size");
+	} else {
+		println!("recommendation: {}={}", gpg::CHUNK_SIZE_ENV, best.1);
+	}
+	Ok(())
+}
+
+/// Compares the input latency of small files sent chunk by chunk and read into memory first.
+async fn run_small_files(input: &Path, output: &Path, logger: &Logger) -> Result<(), OkcError> {
+	std::fs::write(input, vec![0x5au8; SMALL_FILE_LEN as usize])?;
+	println!("sending {} files of {} bytes", SMALL_FILE_OPERATIONS, SMALL_FILE_LEN);
+	for &(label, small_file_size) in &[("streamed", 0), ("in memory", SMALL_FILE_SIZE)] {
+		let mut total = Duration::ZERO;
+		for _ in 0..SMALL_FILE_OPERATIONS {
+			let limits = Limits { small_file_size, ..Limits::default() };
+			total += run_once(input, output, SMALL_FILE_LEN, limits, logger).await?.input;
+		}
+		println!("{:>9}: {:>8.1} µs per input", label, total.as_secs_f64() * 1e6 / SMALL_FILE_OPERATIONS as f64);
+	}
+	Ok(())
+}
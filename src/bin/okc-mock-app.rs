@@ -0,0 +1,140 @@
+//! Plays the app's side of the okc-gpg protocol on the loopback interface, for testing okc-gpg without
+//! Android. Run okc-gpg with OKC_LISTEN_ONLY set to a file, then point this at the same file:
+//!
+//!     OKC_LISTEN_ONLY=/tmp/port okc-gpg --armor < message &
+//!     okc-mock-app /tmp/port - -
+extern crate clap;
+#[macro_use]
+extern crate slog;
+extern crate okc_agents;
+
+use std::time::Duration;
+use slog::Logger;
+use tokio::time;
+use okc_agents::mock;
+use okc_agents::proto::{OP_INPUT, OP_OUTPUT, PROTOCOL_VERSION};
+use okc_agents::utils::*;
+
+// How long to wait for okc-gpg to write its port.
+const PORT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What is done to the input before it's sent back as the output.
+#[derive(Clone, Copy)]
+enum Transform {
+	Echo,
+	Rot13,
+}
+
+impl Transform {
+	fn apply(self, data: &mut [u8]) {
+		if let Self::Rot13 = self {
+			for b in data {
+				*b = match *b {
+					b'a'..=b'z' => (*b - b'a' + 13) % 26 + b'a',
+					b'A'..=b'Z' => (*b - b'A' + 13) % 26 + b'A',
+					b => b,
+				};
+			}
+		}
+	}
+}
+
+struct Script {
+	input: String,
+	output: String,
+	transform: Transform,
+	warning: Option<String>,
+	status: u8,
+}
+
+/// Reads the port from `spec`, either a port number or the file okc-gpg writes it to, which is waited for.
+async fn port(spec: &str) -> Result<u16> {
+	if let Ok(port) = spec.parse() {
+		return Ok(port);
+	}
+	let deadline = time::Instant::now() + PORT_TIMEOUT;
+	loop {
+		if let Some(port) = std::fs::read_to_string(spec).ok().and_then(|s| s.trim().parse().ok()) {
+			return Ok(port);
+		}
+		if time::Instant::now() > deadline {
+			let msg = format!("no port was written to {} within {} seconds", spec, PORT_TIMEOUT.as_secs());
+			return Err(StringError::new(msg).into());
+		}
+		time::sleep(Duration::from_millis(50)).await;
+	}
+}
+
+async fn play(port: u16, script: Script, logger: &Logger) -> Result {
+	let mut data = mock::read_input(port, &[OP_INPUT], &script.input).await?;
+	info!(logger, "read the input"; "path" => &script.input, "bytes" => data.len());
+
+	script.transform.apply(&mut data);
+	mock::write_output(port, &[OP_OUTPUT], &script.output, &data).await?;
+	info!(logger, "wrote the output"; "path" => &script.output, "bytes" => data.len());
+
+	let version = format!("[V] {}", PROTOCOL_VERSION);
+	let mut messages = vec![&version[..]];
+	messages.extend(script.warning.as_deref());
+	mock::finish(port, &messages, script.status).await?;
+	info!(logger, "reported status {}", script.status);
+	Ok(())
+}
+
+fn main() {
+	let matches = clap::App::new("okc-mock-app")
+		.version(env!("CARGO_PKG_VERSION"))
+		.about("Plays the app's side of the okc-gpg protocol for testing without Android.")
+		.arg(clap::Arg::with_name("transform")
+			.short("t")
+			.value_name("TRANSFORM")
+			.takes_value(true)
+			.possible_values(&["echo", "rot13"])
+			.default_value("echo")
+			.help("What to do to the input before sending it back as the output."))
+		.arg(clap::Arg::with_name("warning")
+			.short("w")
+			.value_name("MESSAGE")
+			.takes_value(true)
+			.help("A message to send on the control connection, prefix it with [W] for a warning."))
+		.arg(clap::Arg::with_name("status")
+			.short("s")
+			.value_name("STATUS")
+			.takes_value(true)
+			.default_value("0")
+			.help("The status code to report."))
+		.arg(clap::Arg::with_name("port")
+			.value_name("PORT")
+			.required(true)
+			.index(1)
+			.help("The port okc-gpg listens on, or the file it writes the port to with OKC_LISTEN_ONLY."))
+		.arg(clap::Arg::with_name("input")
+			.value_name("INPUT")
+			.required(true)
+			.index(2)
+			.help("The input path to ask for, - for okc-gpg's stdin."))
+		.arg(clap::Arg::with_name("output")
+			.value_name("OUTPUT")
+			.required(true)
+			.index(3)
+			.help("The output path to write to, - for okc-gpg's stdout."))
+		.get_matches();
+	let status = matches.value_of("status").unwrap().parse::<u8>().unwrap_or_else(|_| {
+		eprintln!("the status must be a number from 0 to 255");
+		terminate(ExitReason::Startup)
+	});
+	let script = Script {
+		input: matches.value_of("input").unwrap().to_owned(),
+		output: matches.value_of("output").unwrap().to_owned(),
+		transform: if matches.value_of("transform") == Some("rot13") { Transform::Rot13 } else { Transform::Echo },
+		warning: matches.value_of("warning").map(str::to_owned),
+		status,
+	};
+	let port_spec = matches.value_of("port").unwrap().to_owned();
+	lib_main(|logger| async move {
+		let port = port(&port_spec).await?;
+		info!(logger, "connecting to okc-gpg"; "port" => port);
+		play(port, script, &logger).await?;
+		terminate(ExitReason::Success)
+	});
+}
@@ -112,17 +112,17 @@ fn cleanup(logger: Option<Logger>) {
 	}
 }
 
-async fn handle_signals(mut signal: Signal, logger: Logger) {
+async fn handle_signals(mut signal: Signal, signum: i32, logger: Logger) {
 	signal.recv().await;
 	cleanup(Some(logger));
-	exit_process(1);
+	terminate(ExitReason::Signal(signum));
 }
 
 async fn run_wrapper(listener: StdUnixListener, cmd: Option<Command>, logger: Logger) -> Result {
 	tokio::spawn(future::join3(
-		handle_signals(signal(SignalKind::hangup())?, logger.clone()),
-		handle_signals(signal(SignalKind::interrupt())?, logger.clone()),
-		handle_signals(signal(SignalKind::terminate())?, logger.clone()),
+		handle_signals(signal(SignalKind::hangup())?, libc::SIGHUP, logger.clone()),
+		handle_signals(signal(SignalKind::interrupt())?, libc::SIGINT, logger.clone()),
+		handle_signals(signal(SignalKind::terminate())?, libc::SIGTERM, logger.clone()),
 	));
 	let run_future = run(listener, logger.clone());
 	if let Some(mut cmd) = cmd {
@@ -133,7 +133,7 @@ async fn run_wrapper(listener: StdUnixListener, cmd: Option<Command>, logger: Lo
 		}));
 		let stat = cmd.status().await;
 		cleanup(Some(logger.clone()));
-		exit_process(stat?.code().unwrap_or(1))
+		terminate(ExitReason::Child(stat?.code()))
 	} else {
 		let res = run_future.await;
 		cleanup(Some(logger.clone()));
@@ -145,7 +145,7 @@ unsafe fn redirect_null(fd: i32, write: bool) {
 	let null_path = CString::new("/dev/null").unwrap();
 	let null_fd = libc::open(null_path.as_ptr(), if write { libc::O_RDWR } else { libc::O_RDONLY });
 	if null_fd == -1 || libc::dup2(null_fd, fd) == -1 {
-		std::process::exit(1);
+		terminate(ExitReason::Startup);
 	}
 }
 
@@ -193,7 +193,7 @@ fn main() {
 
 	let is_csh = matches.is_present("csh") ||
 		!matches.is_present("bash") &&
-			std::env::var("SHELL").map_or(false, |s| s.ends_with("csh"));
+			matches!(std::env::var("SHELL"), Ok(s) if s.ends_with("csh"));
 
 	if matches.is_present("debug") {
 		std::env::set_var("RUST_LOG", "trace");
@@ -206,12 +206,12 @@ fn main() {
 			.and_then(|s| s.parse::<i32>().ok())
 			.unwrap_or_else(|| {
 				eprintln!("failed to read environment variable {}", AGENT_PID_ENV);
-				std::process::exit(1)
+				terminate(ExitReason::Startup)
 			});
 		unsafe {
 			if libc::kill(env_pid, libc::SIGTERM) != 0 {
 				eprintln!("failed to kill the process {}: {:?}", env_pid, io::Error::last_os_error());
-				std::process::exit(1);
+				terminate(ExitReason::Startup);
 			}
 		}
 
@@ -243,7 +243,7 @@ fn main() {
 				let template_ptr = template.into_raw();
 				if libc::mkdtemp(template_ptr).is_null() {
 					eprintln!("failed to create temporary directory: {:?}", io::Error::last_os_error());
-					std::process::exit(1);
+					terminate(ExitReason::Startup);
 				}
 				template = CString::from_raw(template_ptr);
 			}
@@ -255,7 +255,7 @@ fn main() {
 	// Create the socket now so that we can report errors and run specified commands.
 	let listener = StdUnixListener::bind(&socket_file).unwrap_or_else(|e| {
 		eprintln!("failed to create Unix socket {:?}: {:?}", socket_file.to_string_lossy(), e);
-		std::process::exit(1)
+		terminate(ExitReason::Startup)
 	});
 	*SOCKET_FILE.write().unwrap() = Some(socket_file.clone());
 
@@ -268,7 +268,7 @@ fn main() {
 				-1 => {
 					eprintln!("failed to fork: {:?}", io::Error::last_os_error());
 					cleanup(None);
-					std::process::exit(1);
+					terminate(ExitReason::Startup);
 				}
 				0 => {
 					redirect_null(libc::STDIN_FILENO, false);
@@ -0,0 +1,46 @@
+//! CRC-32 (IEEE 802.3, as used by zlib) for the optional transfer checksums.
+
+const POLY: u32 = 0xedb8_8320;
+
+const fn make_table() -> [u32; 256] {
+	let mut table = [0u32; 256];
+	let mut i = 0;
+	while i < 256 {
+		let mut crc = i as u32;
+		let mut bit = 0;
+		while bit < 8 {
+			crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+			bit += 1;
+		}
+		table[i] = crc;
+		i += 1;
+	}
+	table
+}
+
+static TABLE: [u32; 256] = make_table();
+
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Crc32 {
+	pub fn new() -> Self {
+		Self(!0)
+	}
+
+	pub fn update(&mut self, data: &[u8]) {
+		for &b in data {
+			self.0 = TABLE[((self.0 ^ b as u32) & 0xff) as usize] ^ (self.0 >> 8);
+		}
+	}
+
+	pub fn value(self) -> u32 {
+		!self.0
+	}
+}
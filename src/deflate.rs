@@ -0,0 +1,646 @@
+//! A small raw DEFLATE (RFC 1951) codec for the optional compressed transfer mode.
+//!
+//! The encoder only emits fixed-Huffman and stored blocks and sync-flushes after every chunk, so the
+//! app can decompress each frame as soon as it arrives. The decoder accepts any conforming stream.
+
+use std::io;
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+const MAX_STORED: usize = 65535;
+// How much of a chunk is compressed before deciding whether the rest is worth it.
+const PROBE_LEN: usize = 4096;
+
+const LEN_BASE: [u16; 29] = [
+	3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+	1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+	6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+	0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+const CODE_LEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn invalid_data(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, format!("invalid deflate stream: {}", msg))
+}
+
+struct BitWriter {
+	out: Vec<u8>,
+	buf: u64,
+	count: u32,
+}
+
+impl BitWriter {
+	fn new() -> Self {
+		Self { out: Vec::new(), buf: 0, count: 0 }
+	}
+
+	fn write_bits(&mut self, value: u32, n: u32) {
+		self.buf |= (value as u64) << self.count;
+		self.count += n;
+		while self.count >= 8 {
+			self.out.push(self.buf as u8);
+			self.buf >>= 8;
+			self.count -= 8;
+		}
+	}
+
+	// Huffman codes are packed starting from their most significant bit.
+	fn write_code(&mut self, code: u32, n: u32) {
+		self.write_bits(code.reverse_bits() >> (32 - n), n);
+	}
+
+	fn align(&mut self) {
+		if self.count > 0 {
+			self.write_bits(0, 8 - self.count);
+		}
+	}
+}
+
+fn write_fixed_literal(w: &mut BitWriter, sym: u32) {
+	match sym {
+		0..=143 => w.write_code(0x30 + sym, 8),
+		144..=255 => w.write_code(0x190 + sym - 144, 9),
+		256..=279 => w.write_code(sym - 256, 7),
+		_ => w.write_code(0xc0 + sym - 280, 8),
+	}
+}
+
+fn write_fixed_match(w: &mut BitWriter, len: usize, dist: usize) {
+	let li = LEN_BASE.iter().rposition(|&b| b as usize <= len).unwrap();
+	write_fixed_literal(w, 257 + li as u32);
+	w.write_bits((len - LEN_BASE[li] as usize) as u32, LEN_EXTRA[li] as u32);
+	let di = DIST_BASE.iter().rposition(|&b| b as usize <= dist).unwrap();
+	w.write_code(di as u32, 5);
+	w.write_bits((dist - DIST_BASE[di] as usize) as u32, DIST_EXTRA[di] as u32);
+}
+
+fn hash(data: &[u8]) -> usize {
+	let v = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+	(v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Streaming compressor producing a raw DEFLATE stream.
+pub struct Deflater {
+	history: Vec<u8>,
+	/// The position in the stream of `history[0]`. The hash tables hold positions in the stream, so that
+	/// they stay valid when the history is trimmed.
+	base: usize,
+	/// The most recent position in the stream with each hash, or `usize::MAX`.
+	head: Vec<usize>,
+	/// The previous position with the same hash as the one in the window that maps to each slot.
+	prev: Vec<usize>,
+	/// The position in the stream up to which positions have been added to the hash tables.
+	hashed: usize,
+	finished: bool,
+}
+
+impl Default for Deflater {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Deflater {
+	pub fn new() -> Self {
+		Self {
+			history: Vec::new(),
+			base: 0,
+			head: vec![usize::MAX; 1 << HASH_BITS],
+			prev: vec![usize::MAX; WINDOW_SIZE],
+			hashed: 0,
+			finished: false,
+		}
+	}
+
+	/// Adds the positions in the stream before `end` to the hash tables, as far as the 3 bytes a hash needs
+	/// are in `buf`.
+	fn insert(&mut self, buf: &[u8], end: usize) {
+		let end = end.min((self.base + buf.len()).saturating_sub(MIN_MATCH - 1));
+		while self.hashed < end {
+			let h = hash(&buf[self.hashed - self.base..]);
+			self.prev[self.hashed % WINDOW_SIZE] = self.head[h];
+			self.head[h] = self.hashed;
+			self.hashed += 1;
+		}
+	}
+
+	/// Compresses `data` and appends the result, sync-flushed to a byte boundary, to `out`.
+	pub fn compress(&mut self, data: &[u8], out: &mut Vec<u8>) {
+		assert!(!self.finished, "compress called after finish");
+		if data.is_empty() {
+			return;
+		}
+		let start = self.history.len();
+		let mut buf = std::mem::take(&mut self.history);
+		buf.extend_from_slice(data);
+		let base = self.base;
+		// The last positions of the previous chunk couldn't be hashed without the bytes following them.
+		self.insert(&buf, base + start);
+
+		let mut w = BitWriter::new();
+		w.write_bits(0b010, 3);
+		let mut pos = start;
+		let (mut probed, mut compressible) = (false, true);
+		while pos < buf.len() {
+			// Data that has already been compressed or encrypted only grows with fixed codes, so give up on it
+			// early instead of searching the whole chunk for matches that aren't there.
+			if !probed && pos - start >= PROBE_LEN {
+				probed = true;
+				if w.out.len() > pos - start {
+					compressible = false;
+					break;
+				}
+			}
+			let mut best_len = 0;
+			let mut best_dist = 0;
+			if pos + MIN_MATCH <= buf.len() {
+				let max_len = MAX_MATCH.min(buf.len() - pos);
+				let mut cand = self.head[hash(&buf[pos..])];
+				let mut chain = 0;
+				while cand != usize::MAX && base + pos - cand <= WINDOW_SIZE && chain < MAX_CHAIN {
+					let from = cand - base;
+					let len = buf[from..].iter().zip(&buf[pos..pos + max_len]).take_while(|(a, b)| a == b).count();
+					if len > best_len {
+						best_len = len;
+						best_dist = pos - from;
+						if len == max_len {
+							break;
+						}
+					}
+					cand = self.prev[cand % WINDOW_SIZE];
+					chain += 1;
+				}
+			}
+			if best_len >= MIN_MATCH {
+				write_fixed_match(&mut w, best_len, best_dist);
+				pos += best_len;
+			} else {
+				write_fixed_literal(&mut w, buf[pos] as u32);
+				pos += 1;
+			}
+			self.insert(&buf, base + pos);
+		}
+		self.insert(&buf, base + buf.len());
+		write_fixed_literal(&mut w, 256);
+		// An empty stored block realigns the stream so the peer can decode everything sent so far.
+		w.write_bits(0, 3);
+		w.align();
+		w.out.extend_from_slice(&[0, 0, 0xff, 0xff]);
+
+		let stored_len = data.len() + (data.len() + MAX_STORED - 1) / MAX_STORED * 5;
+		if compressible && w.out.len() <= stored_len {
+			out.extend_from_slice(&w.out);
+		} else {
+			for block in data.chunks(MAX_STORED) {
+				let len = block.len() as u16;
+				out.push(0);
+				out.extend_from_slice(&len.to_le_bytes());
+				out.extend_from_slice(&(!len).to_le_bytes());
+				out.extend_from_slice(block);
+			}
+		}
+
+		let keep = buf.len().saturating_sub(WINDOW_SIZE);
+		buf.drain(..keep);
+		self.base += keep;
+		self.history = buf;
+	}
+
+	/// Terminates the stream with an empty final block.
+	pub fn finish(&mut self, out: &mut Vec<u8>) {
+		if !self.finished {
+			self.finished = true;
+			out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+		}
+	}
+}
+
+enum BlockError {
+	Incomplete,
+	Invalid(&'static str),
+}
+
+struct BitReader<'a> {
+	data: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+	fn bits(&mut self, n: u32) -> std::result::Result<u32, BlockError> {
+		let mut v = 0;
+		for i in 0..n {
+			let byte = *self.data.get(self.pos / 8).ok_or(BlockError::Incomplete)?;
+			v |= ((byte >> (self.pos % 8)) as u32 & 1) << i;
+			self.pos += 1;
+		}
+		Ok(v)
+	}
+
+	fn align(&mut self) {
+		self.pos = (self.pos + 7) / 8 * 8;
+	}
+}
+
+struct Huffman {
+	counts: [u16; 16],
+	symbols: Vec<u16>,
+}
+
+impl Huffman {
+	fn new(lengths: &[u8]) -> std::result::Result<Self, BlockError> {
+		let mut counts = [0u16; 16];
+		for &len in lengths {
+			counts[len as usize] += 1;
+		}
+		counts[0] = 0;
+		let mut left = 1i32;
+		for &count in &counts[1..] {
+			left = (left << 1) - count as i32;
+			if left < 0 {
+				return Err(BlockError::Invalid("over-subscribed code lengths"));
+			}
+		}
+		let mut offsets = [0u16; 16];
+		for len in 1..15 {
+			offsets[len + 1] = offsets[len] + counts[len];
+		}
+		let mut symbols = vec![0u16; lengths.len()];
+		for (sym, &len) in lengths.iter().enumerate() {
+			if len != 0 {
+				symbols[offsets[len as usize] as usize] = sym as u16;
+				offsets[len as usize] += 1;
+			}
+		}
+		Ok(Self { counts, symbols })
+	}
+
+	fn decode(&self, r: &mut BitReader) -> std::result::Result<u16, BlockError> {
+		let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+		for len in 1..16 {
+			code |= r.bits(1)? as i32;
+			let count = self.counts[len] as i32;
+			if code - first < count {
+				return Ok(self.symbols[(index + code - first) as usize]);
+			}
+			index += count;
+			first = (first + count) << 1;
+			code <<= 1;
+		}
+		Err(BlockError::Invalid("invalid Huffman code"))
+	}
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+	let mut lengths = [0u8; 288];
+	lengths[..144].iter_mut().for_each(|l| *l = 8);
+	lengths[144..256].iter_mut().for_each(|l| *l = 9);
+	lengths[256..280].iter_mut().for_each(|l| *l = 7);
+	lengths[280..].iter_mut().for_each(|l| *l = 8);
+	let lit = Huffman::new(&lengths).ok().unwrap();
+	let dist = Huffman::new(&[5u8; 30]).ok().unwrap();
+	(lit, dist)
+}
+
+fn dynamic_tables(r: &mut BitReader) -> std::result::Result<(Huffman, Huffman), BlockError> {
+	let nlen = r.bits(5)? as usize + 257;
+	let ndist = r.bits(5)? as usize + 1;
+	let ncode = r.bits(4)? as usize + 4;
+	if nlen > 286 || ndist > 30 {
+		return Err(BlockError::Invalid("too many length or distance codes"));
+	}
+	let mut code_lengths = [0u8; 19];
+	for &i in &CODE_LEN_ORDER[..ncode] {
+		code_lengths[i] = r.bits(3)? as u8;
+	}
+	let code_table = Huffman::new(&code_lengths)?;
+	let mut lengths = vec![0u8; nlen + ndist];
+	let mut i = 0;
+	while i < lengths.len() {
+		let sym = code_table.decode(r)?;
+		let (value, repeat) = match sym {
+			0..=15 => (sym as u8, 1),
+			16 => {
+				let prev = *lengths[..i].last().ok_or(BlockError::Invalid("repeat with no previous length"))?;
+				(prev, 3 + r.bits(2)? as usize)
+			}
+			17 => (0, 3 + r.bits(3)? as usize),
+			_ => (0, 11 + r.bits(7)? as usize),
+		};
+		if i + repeat > lengths.len() {
+			return Err(BlockError::Invalid("too many code lengths"));
+		}
+		lengths[i..i + repeat].iter_mut().for_each(|l| *l = value);
+		i += repeat;
+	}
+	if lengths[256] == 0 {
+		return Err(BlockError::Invalid("missing end-of-block code"));
+	}
+	Ok((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..])?))
+}
+
+/// Where the decoder is within the stream.
+enum State {
+	/// Before a block header.
+	Header,
+	/// Within a stored block, with this many bytes left.
+	Stored(usize),
+	/// Within a Huffman coded block, with its literal/length and distance codes.
+	Coded(Huffman, Huffman),
+	Finished,
+}
+
+/// Streaming decompressor for a raw DEFLATE stream.
+///
+/// Input may be split at arbitrary points. Decoding stops at the last complete symbol and picks up from
+/// there once more input arrives, so only an incomplete symbol or block header is kept between calls.
+pub struct Inflater {
+	pending: Vec<u8>,
+	bit_offset: usize,
+	window: Vec<u8>,
+	state: State,
+	/// Whether the current block is the final one.
+	last: bool,
+}
+
+impl Default for Inflater {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Inflater {
+	pub fn new() -> Self {
+		Self { pending: Vec::new(), bit_offset: 0, window: Vec::new(), state: State::Header, last: false }
+	}
+
+	/// Whether the final block of the stream has been decoded.
+	pub fn is_finished(&self) -> bool {
+		matches!(self.state, State::Finished)
+	}
+
+	/// Feeds compressed bytes and appends the output decoded from them to `out`. Stops early once `out` holds
+	/// `max_len` bytes, keeping the rest of the input: call again with no data until nothing is appended, so
+	/// that a small frame that inflates to a lot of data can't exhaust the memory.
+	pub fn decompress(&mut self, data: &[u8], out: &mut Vec<u8>, max_len: usize) -> io::Result<()> {
+		if self.is_finished() {
+			return if data.is_empty() { Ok(()) } else { Err(invalid_data("data after the final block")) };
+		}
+		self.pending.extend_from_slice(data);
+		let pending = std::mem::take(&mut self.pending);
+		let mut r = BitReader { data: &pending, pos: self.bit_offset };
+		let res = loop {
+			if out.len() >= max_len || self.is_finished() {
+				break Ok(());
+			}
+			let mark = r.pos;
+			match self.step(&mut r, out, max_len - out.len()) {
+				Ok(()) => {}
+				Err(BlockError::Incomplete) => {
+					r.pos = mark;
+					break Ok(());
+				}
+				Err(BlockError::Invalid(msg)) => break Err(invalid_data(msg)),
+			}
+		};
+		let consumed = r.pos / 8;
+		self.bit_offset = r.pos % 8;
+		if self.is_finished() && (r.pos + 7) / 8 < pending.len() {
+			return Err(invalid_data("data after the final block"));
+		}
+		self.pending = pending;
+		self.pending.drain(..consumed);
+		if self.window.len() > 2 * WINDOW_SIZE {
+			let excess = self.window.len() - WINDOW_SIZE;
+			self.window.drain(..excess);
+		}
+		res
+	}
+
+	/// Decodes a block header, a symbol or up to `room` bytes of a stored block. Nothing is output unless it
+	/// succeeds, so that it can be retried from the same position with more input.
+	fn step(&mut self, r: &mut BitReader, out: &mut Vec<u8>, room: usize) -> std::result::Result<(), BlockError> {
+		let end_of_block = match self.state {
+			State::Header => {
+				let last = r.bits(1)? == 1;
+				self.state = match r.bits(2)? {
+					0 => {
+						r.align();
+						let len = r.bits(16)?;
+						if r.bits(16)? != !len & 0xffff {
+							return Err(BlockError::Invalid("stored block length mismatch"));
+						}
+						State::Stored(len as usize)
+					}
+					1 => {
+						let (lit, dist) = fixed_tables();
+						State::Coded(lit, dist)
+					}
+					2 => {
+						let (lit, dist) = dynamic_tables(r)?;
+						State::Coded(lit, dist)
+					}
+					_ => return Err(BlockError::Invalid("reserved block type")),
+				};
+				self.last = last;
+				false
+			}
+			State::Stored(0) => true,
+			State::Stored(ref mut remaining) => {
+				let start = r.pos / 8;
+				let len = (*remaining).min(r.data.len() - start).min(room);
+				if len == 0 {
+					return Err(BlockError::Incomplete);
+				}
+				let block = &r.data[start..start + len];
+				self.window.extend_from_slice(block);
+				out.extend_from_slice(block);
+				r.pos += len * 8;
+				*remaining -= len;
+				false
+			}
+			State::Coded(ref lit, ref dist) => match lit.decode(r)? as usize {
+				sym @ 0..=255 => {
+					self.window.push(sym as u8);
+					out.push(sym as u8);
+					false
+				}
+				256 => true,
+				sym @ 257..=285 => {
+					let li = sym - 257;
+					let len = LEN_BASE[li] as usize + r.bits(LEN_EXTRA[li] as u32)? as usize;
+					let di = dist.decode(r)? as usize;
+					if di >= 30 {
+						return Err(BlockError::Invalid("invalid distance code"));
+					}
+					let d = DIST_BASE[di] as usize + r.bits(DIST_EXTRA[di] as u32)? as usize;
+					if d > self.window.len() {
+						return Err(BlockError::Invalid("distance too far back"));
+					}
+					let from = self.window.len() - d;
+					for i in 0..len {
+						self.window.push(self.window[from + i]);
+					}
+					out.extend_from_slice(&self.window[self.window.len() - len..]);
+					false
+				}
+				_ => return Err(BlockError::Invalid("invalid literal/length code")),
+			},
+			State::Finished => false,
+		};
+		if end_of_block {
+			self.state = if self.last { State::Finished } else { State::Header };
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Deterministic pseudo-random bytes, which don't compress.
+	fn noise(len: usize) -> Vec<u8> {
+		let mut state = 0x2545f4914f6cdd1du64;
+		(0..len).map(|_| {
+			state ^= state << 13;
+			state ^= state >> 7;
+			state ^= state << 17;
+			state as u8
+		}).collect()
+	}
+
+	fn compress(data: &[u8], chunk: usize) -> Vec<u8> {
+		let mut deflater = Deflater::new();
+		let mut out = Vec::new();
+		for chunk in data.chunks(chunk) {
+			deflater.compress(chunk, &mut out);
+		}
+		deflater.finish(&mut out);
+		out
+	}
+
+	/// Feeds `compressed` in pieces of `piece` bytes and drains the output `max_len` bytes at a time.
+	fn decompress(compressed: &[u8], piece: usize, max_len: usize) -> io::Result<Vec<u8>> {
+		let mut inflater = Inflater::new();
+		let mut out = Vec::new();
+		for piece in compressed.chunks(piece) {
+			let mut data = piece;
+			loop {
+				let mut buf = Vec::new();
+				inflater.decompress(data, &mut buf, max_len)?;
+				assert!(buf.len() < max_len + MAX_MATCH, "{} bytes at once", buf.len());
+				data = &[];
+				if buf.is_empty() {
+					break;
+				}
+				out.extend_from_slice(&buf);
+			}
+		}
+		assert!(inflater.is_finished());
+		Ok(out)
+	}
+
+	#[test]
+	fn round_trip() {
+		let text = (0..200_000).map(|i| b"the quick brown fox "[i % 20] ^ (i / 7000) as u8).collect::<Vec<_>>();
+		let inputs = [Vec::new(), b"a".to_vec(), text, noise(150_000)];
+		for data in inputs.iter() {
+			// Chunks that don't line up with the stored block size or the window.
+			for chunk in [1000, 70_000, usize::MAX] {
+				let compressed = compress(data, chunk);
+				for piece in [1, 7, 4096, usize::MAX] {
+					if piece == 1 && data.len() > 1000 && chunk != 1000 {
+						continue;
+					}
+					let out = decompress(&compressed, piece, 65_535).unwrap();
+					assert!(out == *data, "{} bytes in chunks of {}, pieces of {}", data.len(), chunk, piece);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn incompressible_data_is_stored() {
+		let data = noise(100_000);
+		assert!(compress(&data, usize::MAX).len() <= data.len() + 2 * 5 + 5);
+	}
+
+	#[test]
+	fn matches_span_chunks() {
+		let data = noise(20_000).repeat(2);
+		let compressed = compress(&data, 20_000);
+		// The second chunk is one long run of matches against the first, which is stored.
+		assert!(compressed.len() < 20_000 + 1000, "{} bytes", compressed.len());
+		assert!(decompress(&compressed, usize::MAX, 65_535).unwrap() == data);
+	}
+
+	#[test]
+	fn dynamic_block() {
+		// `a` * 64 + `b` from zlib with Z_HUFFMAN_ONLY, decoded bit by bit across the header.
+		let compressed = [
+			0x05, 0xc1, 0x01, 0x01, 0x00, 0x00, 0x00, 0x80, 0x90, 0xad, 0xfa, 0x3f, 0x22, 0x00, 0x00, 0x00, 0x00,
+			0x00, 0x00, 0x00, 0x00, 0x1a,
+		];
+		let mut expected = vec![b'a'; 64];
+		expected.push(b'b');
+		assert_eq!(decompress(&compressed, 1, 65_535).unwrap(), expected);
+	}
+
+	#[test]
+	fn output_is_bounded() {
+		let data = vec![0; 4 << 20];
+		let compressed = compress(&data, usize::MAX);
+		assert!(compressed.len() < 32 << 10, "{} bytes", compressed.len());
+		assert!(decompress(&compressed, usize::MAX, 1000).unwrap() == data);
+	}
+
+	#[test]
+	fn corrupt_input_is_rejected() {
+		let mut far_back = BitWriter::new();
+		far_back.write_bits(0b011, 3);
+		write_fixed_match(&mut far_back, 3, 1);
+		far_back.align();
+		let mut trailing = compress(b"data", usize::MAX);
+		trailing.push(0);
+		let inputs: [(&str, &[u8]); 4] = [
+			("reserved block type", &[0b111]),
+			("stored block length mismatch", &[1, 5, 0, 5, 0]),
+			("distance too far back", &far_back.out),
+			("data after the final block", &trailing),
+		];
+		for (msg, input) in inputs.iter() {
+			let err = Inflater::new().decompress(input, &mut Vec::new(), usize::MAX).unwrap_err();
+			assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+			assert!(err.to_string().contains(msg), "{}: {}", msg, err);
+		}
+		let mut inflater = Inflater::new();
+		inflater.decompress(&compress(b"data", usize::MAX), &mut Vec::new(), usize::MAX).unwrap();
+		assert!(inflater.decompress(b"more", &mut Vec::new(), usize::MAX).is_err());
+	}
+
+	#[test]
+	fn truncated_stream_is_unfinished() {
+		let compressed = compress(b"truncated", usize::MAX);
+		let mut inflater = Inflater::new();
+		let mut out = Vec::new();
+		inflater.decompress(&compressed[..compressed.len() - 5], &mut out, usize::MAX).unwrap();
+		assert_eq!(out, b"truncated");
+		assert!(!inflater.is_finished());
+	}
+
+	#[test]
+	fn arbitrary_input_does_not_panic() {
+		let noise = noise(64 * 2000);
+		for input in noise.chunks(64) {
+			let _ = Inflater::new().decompress(input, &mut Vec::new(), 1 << 20);
+		}
+	}
+}
@@ -0,0 +1,2503 @@
+//! The okc-gpg side of the GPG proxy protocol.
+//!
+//! After the broadcast, the app connects back to the listener once per stream: a single control
+//! connection carrying warnings and the final status code, plus any number of data connections.
+//!
+//! The connections may be opened in any order and served concurrently. The status is only final once
+//! the data connections are done: on success, okc-gpg still accepts the connections the app opened
+//! before reporting it, waits for all of them and fails if any of them does. A data connection that
+//! failed before the status arrived fails the run as well, even if the app reports success afterwards.
+//! Connections opened after the status has been sent are not served.
+//!
+//! Input is pushed by default: once the app has opened an input connection and sent the path, okc-gpg
+//! streams the whole source without waiting for the app. If okc-gpg offers [`CAP_INPUT_PULL`], the app may
+//! pull instead by setting [`OP_FLAG_PULL`] on the connection, so that it controls how much is read ahead.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant};
+use futures_util::future::BoxFuture;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use slog::Logger;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::ReadHalf;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::time;
+use crate::args::{Change, EXTRA_PREFIX, Extra, Profile, take_input_arg, translate};
+use crate::crc32::Crc32;
+use crate::deflate::{Deflater, Inflater};
+use crate::intent::{ExtraValue, Extras};
+use crate::json;
+use crate::record::{Recorder, Tap};
+use crate::proto::*;
+use crate::text::{LineEnding, Normalizer};
+use crate::utils::{ExitReason, OkcError, Result, begin_flush, begin_stdout, exit_code, print_message};
+
+pub const APP_PACKAGE: &str = "org.ddosolitary.okcagent";
+pub const AM_TIMEOUT: Duration = Duration::from_secs(5);
+pub const ANDROID_USER_ENV: &str = "OKC_ANDROID_USER";
+// Compression only pays off for compressible plaintext, already-encrypted data is sent as stored blocks
+// and just costs CPU time, so it has to be enabled explicitly.
+pub const COMPRESS_ENV: &str = "OKC_COMPRESS";
+pub const CAP_COMPRESSION: i32 = 1;
+// `+` in GPG_ARGS can be turned into a space on its way through am, the URL-safe alphabet avoids it.
+pub const URL_SAFE_ARGS_ENV: &str = "OKC_URL_SAFE_ARGS";
+pub const CAP_URL_SAFE_ARGS: i32 = 2;
+// Names the primary output after the original filename the app found in the data, if there is one. `%f` in
+// the template is replaced with that name, see `OP_FLAG_FILENAME`.
+pub const OUTPUT_TEMPLATE_ENV: &str = "OKC_OUTPUT_TEMPLATE";
+pub const CAP_OUTPUT_FILENAME: i32 = 4;
+// Let the app set the permissions and modification time of output files, see `OP_FLAG_METADATA`.
+pub const OUTPUT_METADATA_ENV: &str = "OKC_OUTPUT_METADATA";
+pub const CAP_OUTPUT_METADATA: i32 = 128;
+// A control message with this prefix carries the capabilities the app accepted, as a decimal mask.
+const CAPABILITIES_PREFIX: &str = "[C] ";
+// A control message with this prefix carries the app's CRC-32 of a transfer as `<role tag> <hex> <path>`.
+const CHECKSUM_PREFIX: &str = "[S] ";
+// The first control message may carry the protocol version the app speaks, older apps don't send it.
+const VERSION_PREFIX: &str = "[V] ";
+// A control message with this prefix carries a GnuPG status line such as `[GNUPG:] SIG_CREATED ...`. Only
+// apps that sent their version first are trusted to use it, for older ones it's an ordinary message.
+const STATUS_PREFIX: &str = "[G] ";
+// A control message with this prefix carries the filename the app found for the output, such as the one in an
+// OpenPGP literal data packet. It's only reported, the output is still written where the app asked.
+const FILENAME_PREFIX: &str = "[F] ";
+// A path or file descriptor number the app's status lines are written to verbatim, like gpg's `--status-fd`.
+// Paths are appended to, so the lines of several invocations can be collected.
+pub const STATUS_FD_ENV: &str = "OKC_STATUS_FD";
+pub const CAP_STATUS_LINES: i32 = 64;
+// Fail instead of warning when the app speaks a different protocol version.
+pub const STRICT_VERSION_ENV: &str = "OKC_STRICT_VERSION";
+// Refuse to open paths whose final component is a symlink, so a planted link can't redirect input or output.
+pub const NOFOLLOW_ENV: &str = "OKC_NOFOLLOW";
+pub const CHUNK_SIZE_ENV: &str = "OKC_CHUNK_SIZE";
+// Abort an operation once its data connections have transferred more than this many bytes in total.
+pub const MAX_BYTES_ENV: &str = "OKC_MAX_BYTES";
+// Abort data connections taking longer than this many seconds, whether or not they make progress.
+pub const MAX_CONNECTION_SECS_ENV: &str = "OKC_MAX_CONNECTION_SECS";
+// Input files up to this size are read into memory and sent at once rather than chunk by chunk. Off by default,
+// as `okc-gpg --okc-bench` shows no gain for small files: those fit into a single chunk either way.
+pub const SMALL_FILE_SIZE_ENV: &str = "OKC_SMALL_FILE_SIZE";
+// How many connections are handled at once, further ones are accepted but wait for one of them to finish.
+pub const MAX_CONNECTIONS_ENV: &str = "OKC_MAX_CONNECTIONS";
+pub const BIND_PORT_ENV: &str = "OKC_BIND_PORT";
+// Set to 1 where `pm` isn't usable, e.g. in restricted shells or outside Android.
+pub const SKIP_PM_CHECK_ENV: &str = "OKC_SKIP_PM_CHECK";
+// The receiver component to broadcast to, the package may be left out as in `.GpgProxyReceiver`.
+pub const RECEIVER_ENV: &str = "OKC_RECEIVER";
+// Ask the package manager which of the app's receivers handles `RECEIVER_ACTION` instead of assuming the
+// default one, for app versions that renamed it. The answer is cached for `RECEIVER_CACHE_AGE`.
+pub const DISCOVER_RECEIVER_ENV: &str = "OKC_DISCOVER_RECEIVER";
+pub const RECEIVER_ACTION: &str = "org.ddosolitary.okcagent.action.GPG_PROXY";
+pub const RECEIVER_CACHE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+// Broadcast with this action and, if set, OKC_CATEGORY, for forks registering their receiver by action. The
+// component is then only added if OKC_RECEIVER is set too, otherwise the system resolves the receiver.
+pub const ACTION_ENV: &str = "OKC_ACTION";
+pub const CATEGORY_ENV: &str = "OKC_CATEGORY";
+// Commands run through `sh -c` once the operation has finished, see `run_hook`.
+pub const ON_SUCCESS_ENV: &str = "OKC_ON_SUCCESS";
+pub const ON_FAILURE_ENV: &str = "OKC_ON_FAILURE";
+// Drive a device attached to this machine through `adb shell`, for debugging the app from a desktop. The
+// app's connections are tunneled back to the local listener with `adb reverse`.
+pub const ADB_ENV: &str = "OKC_ADB";
+// An activity started with the same extras if the app hasn't connected within `FALLBACK_DELAY` of the
+// broadcast, for Android versions not delivering broadcasts to stopped apps. The package may be left out,
+// as in `.MainActivity`.
+pub const START_ACTIVITY_ENV: &str = "OKC_START_ACTIVITY";
+// Log the environment `am` (or `adb`) is run with at debug level, to reproduce a failing broadcast by hand.
+pub const DUMP_ENV_ENV: &str = "OKC_DUMP_ENV";
+pub const FALLBACK_DELAY: Duration = Duration::from_secs(2);
+// How long after the broadcast to suggest why the app may be unable to connect.
+pub const CONNECT_WARNING_DELAY: Duration = Duration::from_secs(10);
+// Wait for the open data connections without a time limit after the control connection has reported
+// success, instead of giving up after `DRAIN_TIMEOUT`, for output to slow destinations.
+pub const WAIT_OUTPUT_ENV: &str = "OKC_WAIT_OUTPUT";
+// `lf` or `crlf`: Rewrite the line endings of the primary input before sending it, so text signed here
+// verifies on platforms using the other convention. Binary input is left alone unless this is set.
+pub const TEXT_MODE_ENV: &str = "OKC_TEXT_MODE";
+// `gpg1` or `gpg2`: Translate the arguments of wrappers written for that GnuPG, see `args::translate`.
+pub const GPG_PROFILE_ENV: &str = "OKC_GPG_PROFILE";
+// `stdout` or `stderr`: Where the app's warnings and other messages go, its errors are logged either way.
+pub const APP_MESSAGES_ENV: &str = "OKC_APP_MESSAGES";
+// A path or file descriptor number to write a JSON summary of the outcome to, see `write_result`.
+pub const RESULT_JSON_ENV: &str = "OKC_RESULT_JSON";
+// A path or file descriptor number to write the files read and written by okc-gpg to, see `write_manifest`.
+pub const MANIFEST_ENV: &str = "OKC_MANIFEST";
+// Records the protocol events of the session to this path, see the `record` module. The transferred data is
+// only included if OKC_RECORD_DATA is set too, as it's usually plaintext. OKC_REPLAY plays a recording back.
+pub const RECORD_ENV: &str = "OKC_RECORD";
+pub const RECORD_DATA_ENV: &str = "OKC_RECORD_DATA";
+pub const REPLAY_ENV: &str = "OKC_REPLAY";
+// Only allow the app to open files below this directory, stdin and stdout are still allowed.
+pub const PATH_ROOT_ENV: &str = "OKC_PATH_ROOT";
+// A path or file descriptor number: Instead of broadcasting, write the port there and wait for the app.
+pub const LISTEN_ONLY_ENV: &str = "OKC_LISTEN_ONLY";
+// The directory relative paths are resolved against, instead of the current one. Whichever of the two applies
+// is also sent to the app as GPG_CWD, so relative paths in the arguments mean the same to both sides.
+pub const WORKDIR_ENV: &str = "OKC_WORKDIR";
+// Compare a CRC-32 of each transfer with the one the app reports on the control connection.
+pub const CHECKSUM_ENV: &str = "OKC_CHECKSUM";
+pub const CAP_CHECKSUM: i32 = 8;
+// Stat every output file once it has been written, checking that its size matches what was received and
+// reporting its size and modification time in the result.
+pub const VERIFY_OUTPUT_ENV: &str = "OKC_VERIFY_OUTPUT";
+// Let the app read ranges of input files, see `OP_FLAG_RANGE`.
+pub const INPUT_RANGES_ENV: &str = "OKC_INPUT_RANGES";
+pub const CAP_INPUT_RANGES: i32 = 16;
+// Let the app pull input with credits instead of having it pushed, see `OP_FLAG_PULL`.
+pub const INPUT_PULL_ENV: &str = "OKC_INPUT_PULL";
+pub const CAP_INPUT_PULL: i32 = 32;
+// The index of the argument naming the input file, see `args::take_input_arg`. okc-gpg replaces it with `-`
+// and streams the file itself when the app reads stdin, which then stays available to the wrapper.
+pub const INPUT_ARG_ENV: &str = "OKC_INPUT_ARG";
+// Colon separated paths the primary output is also written to, `-` being stdout.
+pub const OUTPUT_EXTRA_ENV: &str = "OKC_OUTPUT_EXTRA";
+pub const RETRIES_ENV: &str = "OKC_RETRIES";
+pub const RETRY_STATUS_ENV: &str = "OKC_RETRY_STATUS";
+// How many times to broadcast again if all connections from the app drop before it reports a status, e.g.
+// because the system killed it. Only done while the operation can start over: no output has been written
+// and stdin hasn't been read.
+pub const RESUME_ENV: &str = "OKC_RESUME";
+// How long to wait for the app to report a status after its data connections dropped, before assuming it's
+// gone. A dropped control connection is never followed by a status, so it doesn't wait.
+const RESUME_DELAY: Duration = Duration::from_secs(1);
+// The backoff before retrying and resuming, see `Backoff`.
+pub const RETRY_DELAY_MS_ENV: &str = "OKC_RETRY_DELAY_MS";
+pub const RETRY_MAX_DELAY_MS_ENV: &str = "OKC_RETRY_MAX_DELAY_MS";
+pub const RETRY_MULTIPLIER_ENV: &str = "OKC_RETRY_MULTIPLIER";
+// Connections that don't identify themselves within this time are dropped so they can't hold a slot forever,
+// the default for `Limits::handshake_timeout`.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long connections the app opened before reporting its status may take to be accepted.
+const BACKLOG_GRACE: Duration = Duration::from_millis(20);
+// How long the data connections may take to finish after the app has reported success.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Stdin can only be streamed to the app once per process, so only one operation of a batch gets it.
+static STDIN_USED: AtomicBool = AtomicBool::new(false);
+// Android assigns each user a range of this many UIDs, see AID_USER_OFFSET in the AOSP sources.
+const AID_USER_OFFSET: u32 = 100000;
+
+pub fn env_flag(name: &str) -> bool {
+	matches!(std::env::var(name).as_deref(), Ok("1"))
+}
+
+/// Parses the environment variable `name`, treating an empty value like an unset one.
+pub fn env_parse<T: std::str::FromStr>(name: &str) -> Result<Option<T>, OkcError> {
+	match std::env::var(name) {
+		Ok(s) if !s.is_empty() => s.parse().map(Some)
+			.map_err(|_| OkcError::Other(format!("invalid value for {}: {:?}", name, s))),
+		_ => Ok(None),
+	}
+}
+
+fn android_user_id() -> u32 {
+	unsafe { libc::getuid() / AID_USER_OFFSET }
+}
+
+fn target_user(current_user: u32, logger: &Logger) -> Result<Option<String>, OkcError> {
+	let user = match std::env::var(ANDROID_USER_ENV) {
+		Ok(user) if !user.is_empty() => user,
+		_ => {
+			if current_user != 0 {
+				warn!(logger, "running as a secondary Android user but {} is not set, \
+					the broadcast may reach the wrong app instance", ANDROID_USER_ENV; "user_id" => current_user);
+			}
+			return Ok(None);
+		}
+	};
+	if user != "current" && user != "all" && user.parse::<u32>().is_err() {
+		return Err(OkcError::Other(format!(
+			"invalid value for {}: {:?} (expected a user id, \"current\" or \"all\")", ANDROID_USER_ENV, user
+		)));
+	}
+	Ok(Some(user))
+}
+
+/// Resource bounds for the data connections.
+#[derive(Clone, Debug)]
+pub struct Limits {
+	/// How many bytes are read, written and flushed at a time. Can't exceed the maximum frame length.
+	pub chunk_size: usize,
+	/// How many bytes all data connections of an operation may transfer, uncompressed.
+	pub max_bytes: Option<u64>,
+	/// How long a single data connection may take from its handshake on.
+	pub max_connection_duration: Option<Duration>,
+	/// How many connections, including the control connection, are handled concurrently.
+	pub max_connections: usize,
+	/// Input files up to this size are read into memory first, see [`SMALL_FILE_SIZE_ENV`].
+	pub small_file_size: u64,
+	/// How long a connection may take to send its type, see [`HANDSHAKE_TIMEOUT`].
+	pub handshake_timeout: Duration,
+}
+
+impl Default for Limits {
+	fn default() -> Self {
+		Self {
+			chunk_size: u16::MAX as usize, max_bytes: None, max_connection_duration: None, max_connections: 16,
+			small_file_size: 0, handshake_timeout: HANDSHAKE_TIMEOUT,
+		}
+	}
+}
+
+impl Limits {
+	pub fn from_env() -> Result<Self, OkcError> {
+		let mut limits = Self::default();
+		if let Some(chunk_size) = env_parse::<usize>(CHUNK_SIZE_ENV)? {
+			if chunk_size == 0 || chunk_size > u16::MAX as usize {
+				return Err(OkcError::Other(format!("{} must be between 1 and {}", CHUNK_SIZE_ENV, u16::MAX)));
+			}
+			limits.chunk_size = chunk_size;
+		}
+		limits.max_bytes = env_parse(MAX_BYTES_ENV)?;
+		limits.max_connection_duration = env_parse(MAX_CONNECTION_SECS_ENV)?.map(Duration::from_secs);
+		if let Some(max_connections) = env_parse::<usize>(MAX_CONNECTIONS_ENV)? {
+			if max_connections == 0 {
+				return Err(OkcError::Other(format!("{} must be at least 1", MAX_CONNECTIONS_ENV)));
+			}
+			limits.max_connections = max_connections;
+		}
+		if let Some(small_file_size) = env_parse(SMALL_FILE_SIZE_ENV)? {
+			limits.small_file_size = small_file_size;
+		}
+		Ok(limits)
+	}
+}
+
+/// How long to wait before each further attempt: `base`, growing by `multiplier` from one attempt to the
+/// next up to `max`. How many attempts there are is up to each of the retry loops.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+	pub base: Duration,
+	pub max: Duration,
+	pub multiplier: f64,
+}
+
+impl Default for Backoff {
+	fn default() -> Self {
+		Self { base: Duration::from_secs(1), max: Duration::from_secs(30), multiplier: 2.0 }
+	}
+}
+
+impl Backoff {
+	pub fn from_env() -> Result<Self, OkcError> {
+		let mut backoff = Self::default();
+		if let Some(ms) = env_parse(RETRY_DELAY_MS_ENV)? {
+			backoff.base = Duration::from_millis(ms);
+		}
+		if let Some(ms) = env_parse(RETRY_MAX_DELAY_MS_ENV)? {
+			backoff.max = Duration::from_millis(ms);
+		}
+		if let Some(multiplier) = env_parse::<f64>(RETRY_MULTIPLIER_ENV)? {
+			if !(multiplier >= 1.0 && multiplier.is_finite()) {
+				return Err(OkcError::Other(format!("{} must be a number of at least 1", RETRY_MULTIPLIER_ENV)));
+			}
+			backoff.multiplier = multiplier;
+		}
+		Ok(backoff)
+	}
+
+	/// The delay before the attempt following `attempt` earlier ones, starting at 1.
+	pub fn delay(&self, attempt: u32) -> Duration {
+		let factor = self.multiplier.powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+		Duration::from_secs_f64((self.base.as_secs_f64() * factor).min(self.max.as_secs_f64()))
+	}
+}
+
+/// Which failures reported by the app are worth another attempt, e.g. a dialog dismissed by accident.
+#[derive(Clone, Debug, Default)]
+pub struct RetryPolicy {
+	pub retries: u32,
+	pub statuses: Vec<u8>,
+	/// How often the broadcast may be sent again after the app went away, see [`RESUME_ENV`].
+	pub resumes: u32,
+	/// The delays before retrying and resuming.
+	pub backoff: Backoff,
+}
+
+impl RetryPolicy {
+	pub fn from_env(logger: &Logger) -> Result<Self, OkcError> {
+		let retries = env_parse(RETRIES_ENV)?.unwrap_or(0);
+		let statuses = match std::env::var(RETRY_STATUS_ENV) {
+			Ok(s) if !s.is_empty() => s.split(',').map(|code| code.trim().parse::<u8>())
+				.collect::<std::result::Result<Vec<_>, _>>()
+				.map_err(|_| OkcError::Other(format!("invalid value for {}: {:?}", RETRY_STATUS_ENV, s)))?,
+			_ => Vec::new(),
+		};
+		if retries > 0 && statuses.is_empty() {
+			warn!(logger, "{} is set but {} is empty, no status code will be retried", RETRIES_ENV, RETRY_STATUS_ENV);
+		}
+		Ok(Self { retries, statuses, resumes: env_parse(RESUME_ENV)?.unwrap_or(0), backoff: Backoff::from_env()? })
+	}
+
+	pub fn is_retryable(&self, status: u8) -> bool {
+		self.statuses.contains(&status)
+	}
+}
+
+/// Settings for one okc-gpg operation.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+	/// The Android user the broadcast is sent to, passed to `am broadcast --user`.
+	pub android_user: Option<String>,
+	pub compression: bool,
+	/// Encode GPG_ARGS with the URL-safe base64 alphabet.
+	pub url_safe_args: bool,
+	pub no_follow: bool,
+	pub limits: Limits,
+	pub retry: RetryPolicy,
+	/// A fixed port to listen on for debugging, or 0 for an ephemeral one.
+	pub bind_port: u16,
+	/// Whether to ask `pm` if the app is installed before broadcasting.
+	pub check_installed: bool,
+	pub on_success: Option<String>,
+	pub on_failure: Option<String>,
+	/// Run the device side commands through adb, see [`ADB_ENV`].
+	pub adb: bool,
+	pub wait_output: bool,
+	/// The line ending the primary input is normalized to, see [`TEXT_MODE_ENV`].
+	pub text_mode: Option<LineEnding>,
+	pub strict_version: bool,
+	pub gpg_profile: Option<Profile>,
+	/// Where [`LogSink`] writes the app's messages, see [`APP_MESSAGES_ENV`].
+	pub app_messages: MessageStream,
+	pub result_json: Option<String>,
+	pub manifest: Option<String>,
+	/// Where the app's status lines go, see [`STATUS_FD_ENV`].
+	pub status_fd: Option<String>,
+	/// The template for naming the output after the original filename, see [`OUTPUT_TEMPLATE_ENV`].
+	pub output_template: Option<String>,
+	pub output_metadata: bool,
+	pub record: Option<String>,
+	pub record_data: bool,
+	/// The canonical directory paths from the app must stay within, see [`PATH_ROOT_ENV`].
+	pub path_root: Option<PathBuf>,
+	/// Overrides the current directory for relative paths, see [`WORKDIR_ENV`].
+	pub workdir: Option<PathBuf>,
+	pub checksum: bool,
+	pub input_ranges: bool,
+	pub input_pull: bool,
+	/// The argument streamed as stdin, see [`INPUT_ARG_ENV`].
+	pub input_arg: Option<isize>,
+	pub verify_output: bool,
+	/// More destinations for the primary output, see [`OUTPUT_EXTRA_ENV`].
+	pub output_extra: Vec<String>,
+	/// The activity component to fall back to, see [`START_ACTIVITY_ENV`].
+	pub start_activity: Option<String>,
+	/// The receiver component, see [`RECEIVER_ENV`].
+	pub receiver: Option<String>,
+	pub discover_receiver: bool,
+	/// The broadcast's action and category, see [`ACTION_ENV`].
+	pub action: Option<String>,
+	pub category: Option<String>,
+	pub dump_env: bool,
+	/// Where the app's warnings go, logged through [`LogSink`] if there is none.
+	pub warning_sink: Option<Arc<dyn WarningSink>>,
+}
+
+impl Options {
+	pub fn from_env(logger: &Logger) -> Result<Self, OkcError> {
+		let current_user = android_user_id();
+		info!(logger, "running as Android user {}", current_user);
+		Ok(Self {
+			android_user: target_user(current_user, logger)?,
+			compression: env_flag(COMPRESS_ENV),
+			url_safe_args: env_flag(URL_SAFE_ARGS_ENV),
+			no_follow: env_flag(NOFOLLOW_ENV),
+			limits: Limits::from_env()?,
+			retry: RetryPolicy::from_env(logger)?,
+			bind_port: env_parse(BIND_PORT_ENV)?.unwrap_or(0),
+			check_installed: !env_flag(SKIP_PM_CHECK_ENV),
+			on_success: std::env::var(ON_SUCCESS_ENV).ok().filter(|s| !s.is_empty()),
+			on_failure: std::env::var(ON_FAILURE_ENV).ok().filter(|s| !s.is_empty()),
+			adb: env_flag(ADB_ENV),
+			wait_output: env_flag(WAIT_OUTPUT_ENV),
+			text_mode: env_parse(TEXT_MODE_ENV)?,
+			strict_version: env_flag(STRICT_VERSION_ENV),
+			gpg_profile: env_parse(GPG_PROFILE_ENV)?,
+			app_messages: env_parse(APP_MESSAGES_ENV)?.unwrap_or_default(),
+			result_json: std::env::var(RESULT_JSON_ENV).ok().filter(|s| !s.is_empty()),
+			manifest: std::env::var(MANIFEST_ENV).ok().filter(|s| !s.is_empty()),
+			status_fd: std::env::var(STATUS_FD_ENV).ok().filter(|s| !s.is_empty()),
+			output_template: std::env::var(OUTPUT_TEMPLATE_ENV).ok().filter(|s| !s.is_empty()),
+			output_metadata: env_flag(OUTPUT_METADATA_ENV),
+			record: std::env::var(RECORD_ENV).ok().filter(|s| !s.is_empty()),
+			record_data: env_flag(RECORD_DATA_ENV),
+			path_root: match std::env::var(PATH_ROOT_ENV) {
+				Ok(root) if !root.is_empty() => Some(std::fs::canonicalize(&root).map_err(|e| {
+					OkcError::Other(format!("invalid value for {}: {:?} ({})", PATH_ROOT_ENV, root, e))
+				})?),
+				_ => None,
+			},
+			workdir: std::env::var_os(WORKDIR_ENV).filter(|s| !s.is_empty()).map(PathBuf::from),
+			checksum: env_flag(CHECKSUM_ENV),
+			input_ranges: env_flag(INPUT_RANGES_ENV),
+			input_pull: env_flag(INPUT_PULL_ENV),
+			input_arg: env_parse(INPUT_ARG_ENV)?,
+			verify_output: env_flag(VERIFY_OUTPUT_ENV),
+			output_extra: std::env::var(OUTPUT_EXTRA_ENV).unwrap_or_default()
+				.split(':').filter(|s| !s.is_empty()).map(str::to_owned).collect(),
+			start_activity: std::env::var(START_ACTIVITY_ENV).ok().filter(|s| !s.is_empty()),
+			receiver: std::env::var(RECEIVER_ENV).ok().filter(|s| !s.is_empty()),
+			discover_receiver: env_flag(DISCOVER_RECEIVER_ENV),
+			action: std::env::var(ACTION_ENV).ok().filter(|s| !s.is_empty()),
+			category: std::env::var(CATEGORY_ENV).ok().filter(|s| !s.is_empty()),
+			dump_env: env_flag(DUMP_ENV_ENV),
+			warning_sink: None,
+		})
+	}
+
+	/// The directory relative paths are resolved against, which is forwarded to the app.
+	pub fn effective_workdir(&self) -> Option<PathBuf> {
+		self.workdir.clone().or_else(|| std::env::current_dir().ok())
+	}
+
+	pub fn capabilities(&self) -> i32 {
+		let mut capabilities = 0;
+		if self.compression {
+			capabilities |= CAP_COMPRESSION;
+		}
+		if self.url_safe_args {
+			capabilities |= CAP_URL_SAFE_ARGS;
+		}
+		if self.output_template.is_some() {
+			capabilities |= CAP_OUTPUT_FILENAME;
+		}
+		if self.checksum {
+			capabilities |= CAP_CHECKSUM;
+		}
+		if self.input_ranges {
+			capabilities |= CAP_INPUT_RANGES;
+		}
+		if self.input_pull {
+			capabilities |= CAP_INPUT_PULL;
+		}
+		if self.status_fd.is_some() {
+			capabilities |= CAP_STATUS_LINES;
+		}
+		if self.output_metadata {
+			capabilities |= CAP_OUTPUT_METADATA;
+		}
+		capabilities
+	}
+}
+
+/// Tells the app which port to connect back to.
+pub trait Broadcaster {
+	/// Fails early if the broadcast can't possibly be answered, instead of waiting for a connection forever.
+	fn check(&self) -> BoxFuture<'_, Result<(), OkcError>> {
+		Box::pin(async { Ok(()) })
+	}
+
+	/// Sends the broadcast for `port`. The listener is already bound and listening when this is called, so
+	/// the app may connect before it returns.
+	fn send<'a>(&'a self, port: u16, args: &'a [String]) -> BoxFuture<'a, Result<(), OkcError>>;
+
+	/// Releases whatever `send` set up for `port`, called once the operation has finished.
+	fn cleanup(&self, _port: u16) -> BoxFuture<'_, ()> {
+		Box::pin(async {})
+	}
+
+	/// Tries another way of reaching the app, called once if it hasn't connected within [`FALLBACK_DELAY`]
+	/// of `send`. Returns whether there is one.
+	fn fallback<'a>(&'a self, _port: u16, _args: &'a [String]) -> BoxFuture<'a, Result<bool, OkcError>> {
+		Box::pin(async { Ok(false) })
+	}
+}
+
+/// Waits for `cmd` to exit, killing it if it takes longer than [`AM_TIMEOUT`].
+async fn run_timed(cmd: &mut Command, name: &str) -> Result<ExitStatus, OkcError> {
+	let mut child = cmd.kill_on_drop(true).spawn()?;
+	match time::timeout(AM_TIMEOUT, child.wait()).await {
+		Ok(status) => Ok(status?),
+		Err(_) => {
+			child.kill().await?;
+			Err(OkcError::Timeout(format!("{} did not complete within {} seconds", name, AM_TIMEOUT.as_secs())))
+		}
+	}
+}
+
+/// Sends the broadcast with the `am` command.
+pub struct AmBroadcaster {
+	pub user: Option<String>,
+	pub capabilities: i32,
+	pub check_installed: bool,
+	pub adb: bool,
+	pub workdir: Option<PathBuf>,
+	/// Additional extras from the command line.
+	pub extras: Vec<Extra>,
+	pub start_activity: Option<String>,
+	/// The configured receiver component, which takes precedence over discovery.
+	pub receiver: Option<String>,
+	pub discover_receiver: bool,
+	pub action: Option<String>,
+	pub category: Option<String>,
+	pub dump_env: bool,
+	pub logger: Logger,
+}
+
+/// Adds the app's package to `component` if it only names the class.
+fn full_component(component: &str) -> String {
+	if component.contains('/') { component.to_owned() } else { format!("{}/{}", APP_PACKAGE, component) }
+}
+
+/// Quotes `s` for a POSIX shell unless it only consists of characters that are safe as they are.
+fn shell_quote(s: &str) -> String {
+	if !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || "-_.,:/=+@%".contains(c)) {
+		s.to_owned()
+	} else {
+		format!("'{}'", s.replace('\'', "'\\''"))
+	}
+}
+
+// Where the discovered receiver is cached, not used for devices attached over adb.
+fn receiver_cache_path() -> Option<PathBuf> {
+	let cache_dir = match std::env::var_os("XDG_CACHE_HOME").filter(|s| !s.is_empty()) {
+		Some(dir) => PathBuf::from(dir),
+		None => PathBuf::from(std::env::var_os("HOME").filter(|s| !s.is_empty())?).join(".cache"),
+	};
+	Some(cache_dir.join("okc-agents").join("receiver"))
+}
+
+fn read_cached_receiver(path: &Path) -> Option<String> {
+	let age = std::fs::metadata(path).ok()?.modified().ok()?.elapsed().ok()?;
+	if age > RECEIVER_CACHE_AGE {
+		return None;
+	}
+	let receiver = std::fs::read_to_string(path).ok()?.trim().to_owned();
+	if receiver.starts_with(APP_PACKAGE) { Some(receiver) } else { None }
+}
+
+// The extras set by okc-gpg itself, which can't be given with `--okc-extra`.
+const RESERVED_EXTRAS: &[&str] = &["GPG_PROTO_VER", "PROXY_PORT", "GPG_CWD", "GPG_CAPABILITIES", "GPG_ARGS"];
+
+impl AmBroadcaster {
+	pub fn new(options: &Options, logger: Logger) -> Self {
+		Self {
+			user: options.android_user.clone(),
+			capabilities: options.capabilities(),
+			check_installed: options.check_installed,
+			adb: options.adb,
+			workdir: options.effective_workdir(),
+			extras: Vec::new(),
+			start_activity: options.start_activity.clone(),
+			receiver: options.receiver.clone(),
+			discover_receiver: options.discover_receiver,
+			action: options.action.clone(),
+			category: options.category.clone(),
+			dump_env: options.dump_env,
+			logger,
+		}
+	}
+
+	/// Creates a command running `program` on the device.
+	fn device_command(&self, program: &str) -> Command {
+		if self.adb {
+			let mut cmd = Command::new("adb");
+			cmd.arg("shell").arg(program);
+			cmd
+		} else {
+			Command::new(program)
+		}
+	}
+
+	async fn adb_reverse(&self, port: u16) -> Result<(), OkcError> {
+		let tcp = format!("tcp:{}", port);
+		debug!(self.logger, "forwarding device port {} over adb", port);
+		let status = run_timed(
+			Command::new("adb").arg("reverse").arg(&tcp).arg(&tcp).stdout(Stdio::null()), "adb reverse",
+		).await?;
+		if !status.success() {
+			return Err(OkcError::Other(format!("adb reverse failed ({}), check that a device is connected", status)));
+		}
+		Ok(())
+	}
+
+	async fn remove_adb_reverse(&self, port: u16) {
+		let res = run_timed(
+			Command::new("adb").arg("reverse").arg("--remove").arg(format!("tcp:{}", port))
+				.stdout(Stdio::null()).stderr(Stdio::null()),
+			"adb reverse --remove",
+		).await;
+		if let Err(e) = res {
+			warn!(self.logger, "failed to remove the adb port forwarding: {}", e; "port" => port);
+		}
+	}
+
+	async fn run_pm(&self) -> Result<(), OkcError> {
+		let logger = &self.logger;
+		if !self.check_installed {
+			debug!(logger, "skipping the installation check");
+			return Ok(());
+		}
+		let mut cmd = self.device_command("pm");
+		cmd.arg("list").arg("packages");
+		// `pm` doesn't understand "all", any user having the app installed is fine then.
+		if let Some(user) = self.user.as_ref().filter(|user| *user != "all") {
+			cmd.arg("--user").arg(user);
+		}
+		cmd.arg(APP_PACKAGE).stdin(Stdio::null()).stderr(Stdio::null()).kill_on_drop(true);
+		let output = match time::timeout(AM_TIMEOUT, cmd.output()).await {
+			Ok(Ok(output)) if output.status.success() => output,
+			Ok(Ok(output)) => {
+				warn!(logger, "pm failed, skipping the installation check"; "status" => %output.status);
+				return Ok(());
+			}
+			Ok(Err(e)) => {
+				warn!(logger, "failed to run pm, skipping the installation check: {}", e);
+				return Ok(());
+			}
+			Err(_) => {
+				warn!(logger, "pm did not complete within {} seconds, skipping the installation check", AM_TIMEOUT.as_secs());
+				return Ok(());
+			}
+		};
+		let package_line = format!("package:{}", APP_PACKAGE);
+		if !String::from_utf8_lossy(&output.stdout).lines().any(|line| line.trim() == package_line) {
+			return Err(OkcError::Other(format!(
+				"OkcAgent ({}) is not installed, install it or set {}=1 if this check is wrong", APP_PACKAGE, SKIP_PM_CHECK_ENV
+			)));
+		}
+		debug!(logger, "{} is installed", APP_PACKAGE);
+		Ok(())
+	}
+
+	async fn run_am(&self, port: u16, args: &[String]) -> Result<(), OkcError> {
+		let extras = self.extras(port, args)?;
+		if self.adb {
+			self.adb_reverse(port).await?;
+		}
+		let receiver = match self.action {
+			Some(_) if self.receiver.is_none() => None,
+			_ => Some(self.receiver().await),
+		};
+		let mut cmd = self.am_command("broadcast", receiver.as_deref(), &extras);
+		run_timed(&mut cmd, "am").await.map_err(|e| match e {
+			OkcError::Other(msg) => OkcError::Other(format!("{}, the activity manager may be unresponsive", msg)),
+			e => e,
+		})?;
+		Ok(())
+	}
+
+	/// The receiver to broadcast to: the configured one, the discovered one or the default one, in that order.
+	async fn receiver(&self) -> String {
+		if let Some(ref receiver) = self.receiver {
+			return full_component(receiver);
+		}
+		if self.discover_receiver {
+			let cache = if self.adb { None } else { receiver_cache_path() };
+			if let Some(receiver) = cache.as_deref().and_then(read_cached_receiver) {
+				debug!(self.logger, "using cached receiver {}", receiver);
+				return receiver;
+			}
+			match self.query_receiver().await {
+				Ok(receiver) => {
+					info!(self.logger, "discovered receiver {}", receiver);
+					if let Some(cache) = cache {
+						let res = std::fs::create_dir_all(cache.parent().unwrap())
+							.and_then(|_| std::fs::write(&cache, &receiver));
+						if let Err(e) = res {
+							debug!(self.logger, "failed to cache the receiver: {}", e; "path" => %cache.display());
+						}
+					}
+					return receiver;
+				}
+				Err(e) => warn!(self.logger, "receiver discovery failed, using the default one: {}", e),
+			}
+		}
+		format!("{}/.GpgProxyReceiver", APP_PACKAGE)
+	}
+
+	async fn query_receiver(&self) -> Result<String, OkcError> {
+		let mut cmd = self.device_command("cmd");
+		cmd.arg("package").arg("query-receivers").arg("--components");
+		if let Some(user) = self.user.as_ref().filter(|user| *user != "all") {
+			cmd.arg("--user").arg(user);
+		}
+		cmd.arg("-a").arg(RECEIVER_ACTION).arg(APP_PACKAGE)
+			.stdin(Stdio::null()).stderr(Stdio::null()).kill_on_drop(true);
+		let output = time::timeout(AM_TIMEOUT, cmd.output()).await
+			.map_err(|_| OkcError::Timeout(format!("cmd package did not complete within {} seconds", AM_TIMEOUT.as_secs())))??;
+		if !output.status.success() {
+			return Err(OkcError::Other(format!("cmd package failed ({})", output.status)));
+		}
+		let prefix = format!("{}/", APP_PACKAGE);
+		String::from_utf8_lossy(&output.stdout).lines().map(str::trim).find(|line| line.starts_with(&prefix))
+			.map(str::to_owned)
+			.ok_or_else(|| OkcError::Other(format!("no receiver handles {}", RECEIVER_ACTION)))
+	}
+
+	async fn start_activity(&self, port: u16, args: &[String]) -> Result<bool, OkcError> {
+		let component = match self.start_activity {
+			Some(ref activity) => full_component(activity),
+			None => return Ok(false),
+		};
+		info!(self.logger, "the app hasn't connected, starting {}", component);
+		let extras = self.extras(port, args)?;
+		let status = run_timed(&mut self.am_command("start", Some(&component), &extras), "am start").await?;
+		if !status.success() {
+			warn!(self.logger, "am start failed"; "status" => %status);
+		}
+		Ok(true)
+	}
+
+	/// The extras for an operation listening on `port`: okc-gpg's own, then the ones from `--okc-extra`.
+	fn extras(&self, port: u16, args: &[String]) -> Result<Extras, OkcError> {
+		let logger = &self.logger;
+		let app = |name| format!("{}{}", EXTRA_PREFIX, name);
+		let mut extras = Extras::new();
+		extras.insert(app("GPG_PROTO_VER"), ExtraValue::Int(PROTOCOL_VERSION.into()))
+			.and_then(|extras| extras.insert(app("PROXY_PORT"), ExtraValue::Int(port.into())))
+			.map_err(OkcError::Other)?;
+		match self.workdir.as_ref().and_then(|workdir| workdir.to_str()) {
+			Some(workdir) => {
+				debug!(logger, "forwarding working directory {}", workdir);
+				extras.insert(app("GPG_CWD"), ExtraValue::String(workdir.to_owned())).map_err(OkcError::Other)?;
+			}
+			None => debug!(logger, "working directory unknown or not UTF-8, GPG_CWD won't be sent"),
+		}
+		if self.capabilities != 0 {
+			debug!(logger, "offering capabilities {:#x}", self.capabilities);
+			extras.insert(app("GPG_CAPABILITIES"), ExtraValue::Int(self.capabilities)).map_err(OkcError::Other)?;
+		}
+		for extra in &self.extras {
+			if RESERVED_EXTRAS.iter().any(|name| extra.key.strip_prefix(EXTRA_PREFIX) == Some(name)) {
+				return Err(OkcError::Other(format!("extra {} is set by okc-gpg and can't be overridden", extra.key)));
+			}
+			debug!(logger, "adding extra"; "key" => &extra.key, "type" => extra.flag, "value" => &extra.value);
+			extras.insert_extra(extra).map_err(OkcError::Other)?;
+		}
+		if !args.is_empty() {
+			let config = if self.capabilities & CAP_URL_SAFE_ARGS != 0 { base64::URL_SAFE } else { base64::STANDARD };
+			let encoded = args.iter().map(|arg| base64::encode_config(arg, config)).collect();
+			extras.insert(app("GPG_ARGS"), ExtraValue::StringArray(encoded)).map_err(OkcError::Other)?;
+		} else {
+			debug!(logger, "no arguments specified, GPG_ARGS won't be sent")
+		}
+		Ok(extras)
+	}
+
+	/// Creates an `am` command delivering the extras to `component`, either with `broadcast` or `start`.
+	fn am_command(&self, subcommand: &str, component: Option<&str>, extras: &Extras) -> Command {
+		let mut cmd = self.device_command("am");
+		cmd.arg(subcommand);
+		if let Some(ref user) = self.user {
+			debug!(self.logger, "targeting Android user {}", user);
+			// Activities can't be started for all users at once.
+			cmd.arg("--user").arg(if subcommand == "start" && user == "all" { "current" } else { user });
+		}
+		if let Some(component) = component {
+			cmd.arg("-n").arg(component);
+		}
+		// Activities are always started by component, the action only selects receivers.
+		if subcommand == "broadcast" {
+			if let Some(ref action) = self.action {
+				debug!(self.logger, "broadcasting action {}", action);
+				cmd.arg("-a").arg(action);
+			}
+			if let Some(ref category) = self.category {
+				cmd.arg("-c").arg(category);
+			}
+		}
+		cmd.stdout(Stdio::null()).stderr(Stdio::null());
+		for arg in extras.render() {
+			// adb joins the arguments into a command line for the device's shell.
+			cmd.arg(if self.adb { shell_quote(&arg) } else { arg });
+		}
+		if self.dump_env {
+			// The command inherits the whole environment unchanged. Over adb this is only the environment of adb,
+			// the shell on the device has its own.
+			let program = if self.adb { "adb" } else { "am" };
+			for (key, value) in std::env::vars_os().collect::<BTreeMap<_, _>>() {
+				debug!(self.logger, "{} environment: {}={}", program, key.to_string_lossy(), value.to_string_lossy());
+			}
+		}
+		cmd
+	}
+}
+
+impl Broadcaster for AmBroadcaster {
+	fn check(&self) -> BoxFuture<'_, Result<(), OkcError>> {
+		Box::pin(self.run_pm())
+	}
+
+	fn send<'a>(&'a self, port: u16, args: &'a [String]) -> BoxFuture<'a, Result<(), OkcError>> {
+		Box::pin(self.run_am(port, args))
+	}
+
+	fn fallback<'a>(&'a self, port: u16, args: &'a [String]) -> BoxFuture<'a, Result<bool, OkcError>> {
+		Box::pin(self.start_activity(port, args))
+	}
+
+	fn cleanup(&self, port: u16) -> BoxFuture<'_, ()> {
+		Box::pin(async move {
+			if self.adb {
+				self.remove_adb_reverse(port).await;
+			}
+		})
+	}
+}
+
+/// What happened during one operation. Byte counts are before compression.
+#[derive(Debug, Default)]
+pub struct Stats {
+	/// Bytes sent to the app.
+	pub input_bytes: AtomicU64,
+	/// Bytes received from the app.
+	pub output_bytes: AtomicU64,
+	/// Accepted connections, including the control connection.
+	pub connections: AtomicU64,
+	/// The messages received on the control connection.
+	pub warnings: Mutex<Vec<String>>,
+	/// The output files checked because of [`VERIFY_OUTPUT_ENV`].
+	pub outputs: Mutex<Vec<OutputFile>>,
+	/// The files whose transfer finished, see [`MANIFEST_ENV`].
+	pub files: Mutex<Vec<TouchedFile>>,
+	/// The output filename the app suggested on the control connection.
+	pub suggested_filename: Mutex<Option<String>>,
+	pub phases: Mutex<Phases>,
+	/// Set once stdin has been streamed to the app, which rules out sending the broadcast again.
+	pub stdin_read: AtomicBool,
+}
+
+/// How long the phases of an operation took. After a resume, the broadcast and first connection are the
+/// ones of the first try, the status wait is the one of the last control connection.
+#[derive(Clone, Debug, Default)]
+pub struct Phases {
+	/// Sending the broadcast.
+	pub broadcast: Option<Duration>,
+	/// From the broadcast having been sent until the app's first connection was accepted.
+	pub first_connection: Option<Duration>,
+	/// From the control connection being established until the app reported its status.
+	pub status_wait: Option<Duration>,
+	/// Each data connection from its handshake until it finished, whether it succeeded or not.
+	pub connections: Vec<ConnectionTiming>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectionTiming {
+	pub id: u64,
+	pub role: &'static str,
+	pub duration: Duration,
+}
+
+/// Formats `duration` as milliseconds with microsecond precision, as quick phases would all be 0 otherwise.
+fn json_ms(duration: Duration) -> String {
+	format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
+
+impl Phases {
+	fn to_json(&self) -> String {
+		let connections = self.connections.iter().map(|timing| json::Object::new()
+			.raw("id", timing.id)
+			.str("role", timing.role)
+			.raw("ms", json_ms(timing.duration))
+			.finish());
+		json::Object::new()
+			.opt("broadcast_ms", self.broadcast.map(json_ms))
+			.opt("first_connection_ms", self.first_connection.map(json_ms))
+			.opt("status_wait_ms", self.status_wait.map(json_ms))
+			.raw("connections", json::array(connections))
+			.finish()
+	}
+}
+
+/// A file read or written by okc-gpg, `-` standing for stdin and stdout.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TouchedFile {
+	/// The role of the connection, or `extra` for the destinations from [`OUTPUT_EXTRA_ENV`].
+	pub role: &'static str,
+	/// The path as opened, made absolute.
+	pub path: String,
+	pub bytes: u64,
+}
+
+impl TouchedFile {
+	fn to_json(&self) -> String {
+		json::Object::new().str("role", self.role).str("path", &self.path).raw("bytes", self.bytes).finish()
+	}
+}
+
+/// An output file as found on disk after it was written.
+#[derive(Clone, Debug)]
+pub struct OutputFile {
+	pub path: String,
+	pub size: u64,
+	pub modified: Option<std::time::SystemTime>,
+}
+
+impl OutputFile {
+	/// The modification time in milliseconds since the Unix epoch.
+	fn modified_ms(&self) -> Option<u128> {
+		self.modified.and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok()).map(|time| time.as_millis())
+	}
+
+	fn to_json(&self) -> String {
+		json::Object::new()
+			.str("path", &self.path)
+			.raw("size", self.size)
+			.opt("modified_ms", self.modified_ms())
+			.finish()
+	}
+}
+
+struct Session<'a> {
+	options: &'a Options,
+	control_seen: AtomicBool,
+	/// Set once the app has reported success, after which failing data connections fail the run.
+	succeeded: AtomicBool,
+	/// The bytes transferred so far, see [`Limits::max_bytes`].
+	transferred: AtomicU64,
+	stats: &'a Stats,
+	recorder: Option<Recorder>,
+	/// The checksums of the finished transfers, by role and the path the app sent.
+	checksums: Mutex<Vec<(Role, String, u32)>>,
+	/// The checksums the app reported before the transfer finished, compared once it does.
+	pending_checksums: Mutex<Vec<(Role, String, u32)>>,
+	/// Set once an output destination has been opened, after which the operation can't be resumed.
+	output_started: AtomicBool,
+	/// Set when a data connection has been dropped by the app, see [`RESUME_ENV`].
+	data_dropped: AtomicBool,
+	/// The first error of a data connection that failed before the app reported its status.
+	data_error: Mutex<Option<OkcError>>,
+	/// The file the app gets when it reads stdin, see [`INPUT_ARG_ENV`].
+	input_file: Option<String>,
+}
+
+impl<'a> Session<'a> {
+	/// Whether the broadcast can be sent again without the app seeing or producing anything twice.
+	fn can_resume(&self) -> bool {
+		!self.output_started.load(Ordering::SeqCst) && !self.stats.stdin_read.load(Ordering::SeqCst)
+	}
+
+	/// Forgets the connections of an app instance that went away, so that the next one starts over.
+	fn reset(&self) {
+		self.control_seen.store(false, Ordering::SeqCst);
+		self.data_dropped.store(false, Ordering::SeqCst);
+		self.data_error.lock().unwrap().take();
+		self.transferred.store(0, Ordering::SeqCst);
+		self.stats.input_bytes.store(0, Ordering::SeqCst);
+		self.stats.connections.store(0, Ordering::SeqCst);
+		self.stats.warnings.lock().unwrap().clear();
+		self.stats.files.lock().unwrap().clear();
+		self.stats.suggested_filename.lock().unwrap().take();
+		self.stats.phases.lock().unwrap().connections.clear();
+		self.checksums.lock().unwrap().clear();
+		self.pending_checksums.lock().unwrap().clear();
+	}
+
+	/// Records the checksum of a finished transfer and compares it with the app's if that came first.
+	fn add_checksum(&self, role: Role, path: String, copied: Copied, logger: &Logger) -> Result<(), OkcError> {
+		let actual = match copied.checksum {
+			Some(checksum) => checksum,
+			None => return Ok(()),
+		};
+		let expected = {
+			let mut pending = self.pending_checksums.lock().unwrap();
+			let index = pending.iter().position(|(r, p, _)| *r == role && *p == path);
+			index.map(|index| pending.remove(index).2)
+		};
+		if let Some(expected) = expected {
+			compare_checksum(role, &path, expected, actual, logger)?;
+		}
+		self.checksums.lock().unwrap().push((role, path, actual));
+		Ok(())
+	}
+
+	/// Compares a checksum reported by the app with the one computed for the same transfer.
+	fn verify_checksum(&self, msg: &str, logger: &Logger) -> Result<(), OkcError> {
+		let invalid = || OkcError::protocol(format!("invalid checksum message {:?}", msg));
+		let mut fields = msg.splitn(3, ' ');
+		let role = fields.next().and_then(|tag| tag.parse().ok()).and_then(Role::from_tag).ok_or_else(invalid)?;
+		let expected = fields.next().and_then(|crc| u32::from_str_radix(crc, 16).ok()).ok_or_else(invalid)?;
+		let path = fields.next().ok_or_else(invalid)?;
+		let checksums = self.checksums.lock().unwrap();
+		match checksums.iter().find(|(r, p, _)| *r == role && p == path) {
+			Some((_, _, actual)) => compare_checksum(role, path, expected, *actual, logger),
+			None => {
+				debug!(logger, "the app sent a checksum for a transfer that hasn't finished yet"; "role" => role.name(), "path" => path);
+				self.pending_checksums.lock().unwrap().push((role, path.to_owned(), expected));
+				Ok(())
+			}
+		}
+	}
+
+	/// Fails if the app sent a checksum for a transfer that never finished.
+	fn check_pending_checksums(&self) -> Result<(), OkcError> {
+		match self.pending_checksums.lock().unwrap().first() {
+			Some((role, path, _)) => Err(OkcError::Other(format!(
+				"the app sent a checksum for {} {:?}, but no such transfer finished", role.name(), path,
+			))),
+			None => Ok(()),
+		}
+	}
+
+	fn open_options(&self) -> OpenOptions {
+		let mut options = OpenOptions::new();
+		if self.options.no_follow {
+			options.custom_flags(libc::O_NOFOLLOW);
+		}
+		options
+	}
+
+	fn map_open_error(&self, e: io::Error, path: &str) -> OkcError {
+		if self.options.no_follow && e.raw_os_error() == Some(libc::ELOOP) {
+			OkcError::Other(format!("refusing to follow symlink {:?} ({} is set)", path, NOFOLLOW_ENV))
+		} else {
+			OkcError::Io(e)
+		}
+	}
+
+	fn add_file(&self, role: &'static str, path: &str, bytes: u64) {
+		let path = if path == "-" {
+			path.to_owned()
+		} else {
+			let resolved = self.resolve(path);
+			absolute(&resolved).unwrap_or(resolved).to_string_lossy().into_owned()
+		};
+		self.stats.files.lock().unwrap().push(TouchedFile { role, path, bytes });
+	}
+
+	/// Resolves relative paths from the app against the working directory, see [`WORKDIR_ENV`].
+	fn resolve(&self, path: &str) -> PathBuf {
+		match self.options.workdir {
+			Some(ref workdir) => workdir.join(path),
+			None => PathBuf::from(path),
+		}
+	}
+
+	/// Rejects paths outside of the configured root. Files that don't exist yet are checked by their parent,
+	/// a dangling symlink is rejected since the file would be created wherever it points.
+	async fn check_path(&self, path: &Path) -> Result<(), OkcError> {
+		let root = match self.options.path_root {
+			Some(ref root) => root,
+			None => return Ok(()),
+		};
+		let resolved = match (tokio::fs::canonicalize(path).await, path.parent(), path.file_name()) {
+			(Ok(resolved), _, _) => resolved,
+			(Err(e), Some(parent), Some(name)) if e.kind() == io::ErrorKind::NotFound => {
+				if tokio::fs::symlink_metadata(path).await.is_ok() {
+					return Err(OkcError::Other(format!(
+						"refusing to follow the dangling symlink {:?} ({} is set)", path, PATH_ROOT_ENV
+					)));
+				}
+				let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+				tokio::fs::canonicalize(parent).await?.join(name)
+			}
+			(Err(e), _, _) => return Err(e.into()),
+		};
+		if !resolved.starts_with(root) {
+			return Err(OkcError::Other(format!(
+				"refusing to access {:?} outside of {:?} ({} is set)", path, root, PATH_ROOT_ENV
+			)));
+		}
+		Ok(())
+	}
+
+	/// Checks that the file at `path` has the `len` bytes written to it, see [`VERIFY_OUTPUT_ENV`].
+	async fn verify_output(&self, path: &str, len: u64, logger: &Logger) -> Result<(), OkcError> {
+		let metadata = tokio::fs::metadata(self.resolve(path)).await
+			.map_err(|e| OkcError::Other(format!("failed to verify output {:?}: {}", path, e)))?;
+		if !metadata.is_file() {
+			debug!(logger, "not verifying output that isn't a regular file"; "path" => path);
+			return Ok(());
+		}
+		if metadata.len() != len {
+			return Err(OkcError::Other(format!(
+				"output {:?} has {} bytes after writing {}, it may be incomplete or modified by something else",
+				path, metadata.len(), len,
+			)));
+		}
+		let output = OutputFile { path: path.to_owned(), size: metadata.len(), modified: metadata.modified().ok() };
+		info!(logger, "output verified"; "path" => path, "size" => output.size, "modified_ms" => output.modified_ms());
+		self.stats.outputs.lock().unwrap().push(output);
+		Ok(())
+	}
+
+	async fn open_input(&self, path: &str) -> Result<File, OkcError> {
+		check_path_syntax(path)?;
+		let resolved = self.resolve(path);
+		self.check_path(&resolved).await?;
+		let file = self.open_options().read(true).open(&resolved).await.map_err(|e| self.map_open_error(e, path))?;
+		// Opening a directory for reading succeeds, only reading fails with a less helpful error.
+		if file.metadata().await?.is_dir() {
+			return Err(OkcError::Other(format!("input path {:?} is a directory", path)));
+		}
+		Ok(file)
+	}
+
+	async fn create_output(&self, path: &str) -> Result<File, OkcError> {
+		check_path_syntax(path)?;
+		let resolved = self.resolve(path);
+		self.check_path(&resolved).await?;
+		let mut options = self.open_options();
+		options.write(true);
+		match tokio::fs::metadata(&resolved).await {
+			Ok(metadata) if metadata.is_dir() => {
+				return Err(OkcError::Other(format!("output path {:?} is a directory", path)));
+			}
+			// FIFOs and devices are written to as they are, there is nothing to create or truncate.
+			Ok(metadata) if !metadata.is_file() => {}
+			// A symlink created since the check must not be followed out of the root.
+			Err(e) if e.kind() == io::ErrorKind::NotFound && self.options.path_root.is_some() => {
+				options.create_new(true);
+			}
+			_ => {
+				options.create(true).truncate(true);
+			}
+		}
+		options.open(&resolved).await.map_err(|e| self.map_open_error(e, path))
+	}
+}
+
+fn compare_checksum(role: Role, path: &str, expected: u32, actual: u32, logger: &Logger) -> Result<(), OkcError> {
+	if expected != actual {
+		return Err(OkcError::Other(format!(
+			"checksum mismatch for {} {:?}: the app computed {:08x} but okc-gpg {:08x}, the data may be corrupted",
+			role.name(), path, expected, actual,
+		)));
+	}
+	debug!(logger, "checksum verified"; "role" => role.name(), "path" => path, "crc32" => format!("{:08x}", actual));
+	Ok(())
+}
+
+/// Rejects paths that can't mean what the app intended here, such as Windows paths, which would
+/// otherwise be taken as oddly named relative paths.
+fn check_path_syntax(path: &str) -> Result<(), OkcError> {
+	let bytes = path.as_bytes();
+	let reason = if path.is_empty() {
+		"it is empty"
+	} else if path.contains('\0') {
+		"it contains a NUL byte"
+	} else if cfg!(unix) && bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+		&& (bytes[2] == b'\\' || bytes[2] == b'/') {
+		"it starts with a Windows drive letter"
+	} else if cfg!(unix) && path.starts_with("\\\\") {
+		"it is a Windows network path"
+	} else {
+		return Ok(());
+	};
+	Err(OkcError::Other(format!("invalid path {:?}: {}", path, reason)))
+}
+
+/// How the data on one connection is transferred.
+#[derive(Clone, Copy)]
+pub struct Transfer<'a> {
+	pub limits: &'a Limits,
+	pub compressed: bool,
+	/// Whether to compute a CRC-32 of the data, see [`CHECKSUM_ENV`].
+	pub checksum: bool,
+	/// Whether the app pulls the input with credits, see [`OP_FLAG_PULL`].
+	pub pull: bool,
+	pub tap: Tap<'a>,
+	/// The bytes transferred by the operation so far, checked against [`Limits::max_bytes`].
+	pub used: Option<&'a AtomicU64>,
+}
+
+impl<'a> Transfer<'a> {
+	/// A plain transfer that isn't compressed, checksummed, recorded or counted.
+	pub fn plain(limits: &'a Limits) -> Self {
+		Self { limits, compressed: false, checksum: false, pull: false, tap: Tap::none(), used: None }
+	}
+
+	fn count(&self, len: usize) -> Result<(), OkcError> {
+		if let (Some(max_bytes), Some(used)) = (self.limits.max_bytes, self.used) {
+			if used.fetch_add(len as u64, Ordering::SeqCst) + len as u64 > max_bytes {
+				return Err(OkcError::Other(format!(
+					"the operation exceeded the limit of {} bytes set by {}", max_bytes, MAX_BYTES_ENV
+				)));
+			}
+		}
+		Ok(())
+	}
+}
+
+/// The result of a transfer. The length is of the uncompressed data, which the checksum is computed over.
+#[derive(Clone, Copy, Debug)]
+pub struct Copied {
+	pub len: u64,
+	pub checksum: Option<u32>,
+}
+
+/// Sends the data read from `rx` to `tx`. If there are `credits`, only as much is read as they allow.
+async fn copy_input(
+	rx: &mut (impl AsyncRead + Unpin), tx: &mut (impl AsyncWrite + Unpin), mut credits: Option<&mut ReadHalf<'_>>,
+	transfer: Transfer<'_>, text_mode: Option<LineEnding>, logger: &Logger,
+) -> Result<Copied, OkcError> {
+	let Transfer { limits, compressed, tap, .. } = transfer;
+	let mut buf = vec![0u8; limits.chunk_size];
+	let mut total = 0;
+	let mut crc = if transfer.checksum { Some(Crc32::new()) } else { None };
+	let mut normalizer = text_mode.map(Normalizer::new);
+	let mut text_buf = Vec::new();
+	let mut deflater = if compressed { Some(Deflater::new()) } else { None };
+	let mut compressed_buf = Vec::new();
+	let mut credit = 0;
+	loop {
+		let mut want = buf.len();
+		if let Some(ref mut credits) = credits {
+			if credit == 0 {
+				let len = read_u32(credits).await?;
+				tap.credit(len);
+				debug!(logger, "received a credit of {} bytes", len);
+				credit = len as usize;
+			}
+			want = want.min(credit);
+		}
+		let len = if want == 0 { 0 } else { rx.read(&mut buf[..want]).await? };
+		credit = credit.saturating_sub(len);
+		debug!(logger, "sending {} bytes", len);
+		transfer.count(len)?;
+		total += len as u64;
+		let data = match normalizer {
+			Some(ref mut normalizer) => {
+				text_buf.clear();
+				if len == 0 {
+					normalizer.finish(&mut text_buf);
+				} else {
+					normalizer.convert(&buf[..len], &mut text_buf);
+				}
+				&text_buf[..]
+			}
+			None => &buf[..len],
+		};
+		if let Some(ref mut crc) = crc {
+			crc.update(data);
+		}
+		match deflater {
+			Some(ref mut deflater) => {
+				compressed_buf.clear();
+				if !data.is_empty() {
+					deflater.compress(data, &mut compressed_buf);
+				}
+				if len == 0 {
+					deflater.finish(&mut compressed_buf);
+				}
+				debug!(logger, "compressed to {} bytes", compressed_buf.len());
+				write_frames(tx, &compressed_buf).await?;
+				if !compressed_buf.is_empty() {
+					tap.sent(compressed_buf.len(), &compressed_buf);
+				}
+			}
+			None => {
+				write_frames(tx, data).await?;
+				if !data.is_empty() {
+					tap.sent(data.len(), data);
+				}
+			}
+		}
+		tx.flush().await?;
+		if len == 0 { break; }
+	}
+	tx.write_u16(0).await?;
+	tx.flush().await?;
+	tap.sent(0, &[]);
+	Ok(Copied { len: total, checksum: crc.map(Crc32::value) })
+}
+
+/// Adds a hint about the likely cause to errors writing to `dest`, since a full disk, a closed pipe and a
+/// closed descriptor need very different fixes.
+fn write_error(e: io::Error, dest: &str) -> OkcError {
+	let reason = if e.raw_os_error() == Some(libc::ENOSPC) {
+		"no space left on device".to_owned()
+	} else if e.kind() == io::ErrorKind::BrokenPipe {
+		"the reading end of the pipe has been closed".to_owned()
+	} else if e.raw_os_error() == Some(libc::EBADF) {
+		"the file descriptor is closed".to_owned()
+	} else if e.kind() == io::ErrorKind::WriteZero {
+		"the destination doesn't accept any more data".to_owned()
+	} else {
+		e.to_string()
+	};
+	OkcError::Io(io::Error::new(e.kind(), format!("failed to write to {}: {}", dest, reason)))
+}
+
+/// Forwards the frames read from `rx` to `tx` until the terminating empty frame and returns the number of
+/// bytes written. `write_all` keeps retrying short writes and fails with [`io::ErrorKind::WriteZero`] if `tx`
+/// stops accepting data, so nothing is silently dropped.
+pub async fn copy_output(
+	rx: &mut (impl AsyncRead + Unpin), tx: &mut (impl AsyncWrite + Unpin),
+	dest: &str, transfer: Transfer<'_>, logger: &Logger,
+) -> Result<Copied, OkcError> {
+	let Transfer { limits, compressed, tap, .. } = transfer;
+	let mut buf = vec![0u8; limits.chunk_size];
+	let mut total = 0;
+	let mut crc = if transfer.checksum { Some(Crc32::new()) } else { None };
+	let mut inflater = if compressed { Some(Inflater::new()) } else { None };
+	let mut decompressed_buf = Vec::new();
+	let mut frame_buf = Vec::new();
+	loop {
+		let frame_len = read_len(rx).await? as usize;
+		let mut remaining = frame_len;
+		debug!(logger, "{} bytes received", remaining);
+		if remaining == 0 {
+			tap.frame(0, &[]);
+			if matches!(inflater, Some(ref inflater) if !inflater.is_finished()) {
+				return Err(OkcError::Other("compressed output stream ended prematurely".to_owned()));
+			}
+			return Ok(Copied { len: total, checksum: crc.map(Crc32::value) });
+		}
+		// Frames may be larger than a chunk, so they are forwarded piecewise to bound memory usage.
+		while remaining > 0 {
+			let len = remaining.min(buf.len());
+			read_full(rx, &mut buf[..len]).await?;
+			remaining -= len;
+			if tap.records_data() {
+				frame_buf.extend_from_slice(&buf[..len]);
+			}
+			match inflater {
+				Some(ref mut inflater) => {
+					// Inflated a chunk at a time, so that the limit is checked before the data piles up.
+					let mut data = &buf[..len];
+					loop {
+						decompressed_buf.clear();
+						inflater.decompress(data, &mut decompressed_buf, buf.len())?;
+						data = &[];
+						if decompressed_buf.is_empty() {
+							break;
+						}
+						debug!(logger, "decompressed {} bytes", decompressed_buf.len());
+						transfer.count(decompressed_buf.len())?;
+						tx.write_all(&decompressed_buf).await.map_err(|e| write_error(e, dest))?;
+						total += decompressed_buf.len() as u64;
+						if let Some(ref mut crc) = crc {
+							crc.update(&decompressed_buf);
+						}
+					}
+				}
+				None => {
+					transfer.count(len)?;
+					tx.write_all(&buf[..len]).await.map_err(|e| write_error(e, dest))?;
+					total += len as u64;
+					if let Some(ref mut crc) = crc {
+						crc.update(&buf[..len]);
+					}
+				}
+			}
+			tx.flush().await.map_err(|e| write_error(e, dest))?;
+		}
+		tap.frame(frame_len, &frame_buf);
+		frame_buf.clear();
+	}
+}
+
+type Sink = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Writes everything written to `primary` to the extra outputs too, see [`OUTPUT_EXTRA_ENV`]. A write only
+/// completes once all extras have taken the bytes the primary accepted, and the first extra that fails is
+/// kept in `failed` so that the error names it rather than the primary.
+struct FanOut<'a, W> {
+	primary: &'a mut W,
+	extras: Vec<(String, Sink, usize)>,
+	pending: Vec<u8>,
+	failed: Option<OkcError>,
+}
+
+impl<'a, W: AsyncWrite + Unpin> FanOut<'a, W> {
+	fn new(primary: &'a mut W, extras: Vec<(String, Sink)>) -> Self {
+		let extras = extras.into_iter().map(|(path, sink)| (path, sink, 0)).collect();
+		Self { primary, extras, pending: Vec::new(), failed: None }
+	}
+
+	fn fail(&mut self, e: io::Error, path: &str) -> io::Error {
+		let kind = e.kind();
+		self.failed = Some(write_error(e, path));
+		io::Error::new(kind, format!("failed to write to {}", path))
+	}
+
+	fn poll_extras(
+		&mut self, cx: &mut Context<'_>, poll: impl Fn(&mut Sink, &mut Context<'_>) -> Poll<io::Result<()>>,
+	) -> Poll<io::Result<()>> {
+		for i in 0..self.extras.len() {
+			if let Err(e) = ready!(poll(&mut self.extras[i].1, cx)) {
+				let path = self.extras[i].0.clone();
+				return Poll::Ready(Err(self.fail(e, &path)));
+			}
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for FanOut<'_, W> {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let this = &mut *self;
+		// A non-empty buffer means the primary took these bytes on an earlier call that had to wait for
+		// an extra, and the caller is retrying with the same data.
+		if this.pending.is_empty() {
+			let len = ready!(Pin::new(&mut *this.primary).poll_write(cx, buf))?;
+			this.pending.extend_from_slice(&buf[..len]);
+			for extra in this.extras.iter_mut() {
+				extra.2 = 0;
+			}
+		}
+		for i in 0..this.extras.len() {
+			while this.extras[i].2 < this.pending.len() {
+				let (ref path, ref mut sink, ref mut written) = this.extras[i];
+				match ready!(Pin::new(sink).poll_write(cx, &this.pending[*written..])) {
+					Ok(0) => {
+						let path = path.clone();
+						return Poll::Ready(Err(this.fail(io::ErrorKind::WriteZero.into(), &path)));
+					}
+					Ok(len) => *written += len,
+					Err(e) => {
+						let path = path.clone();
+						return Poll::Ready(Err(this.fail(e, &path)));
+					}
+				}
+			}
+		}
+		let len = this.pending.len();
+		this.pending.clear();
+		Poll::Ready(Ok(len))
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		ready!(self.poll_extras(cx, |sink, cx| Pin::new(sink).poll_flush(cx)))?;
+		Pin::new(&mut *self.primary).poll_flush(cx)
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		ready!(self.poll_extras(cx, |sink, cx| Pin::new(sink).poll_shutdown(cx)))?;
+		Pin::new(&mut *self.primary).poll_shutdown(cx)
+	}
+}
+
+/// Receives the warnings and errors the app sends on the control connection, for embedders showing them
+/// in their own UI. Messages keep their `[E] ` or `[W] ` prefix if they have one.
+pub trait WarningSink: Send + Sync {
+	fn warning(&self, msg: &str);
+}
+
+impl std::fmt::Debug for dyn WarningSink {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("WarningSink")
+	}
+}
+
+/// The standard stream messages are written to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MessageStream {
+	Stdout,
+	#[default]
+	Stderr,
+}
+
+impl std::str::FromStr for MessageStream {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, ()> {
+		match s {
+			"stdout" => Ok(Self::Stdout),
+			"stderr" => Ok(Self::Stderr),
+			_ => Err(()),
+		}
+	}
+}
+
+/// Logs errors at their level. Warnings are logged too if the messages go to stderr and printed like
+/// `warning: <msg>` otherwise, other messages are printed to `stream` as they are. Messages for stdout
+/// wait until the output written there is done.
+pub struct LogSink {
+	pub logger: Logger,
+	pub stream: MessageStream,
+}
+
+impl WarningSink for LogSink {
+	fn warning(&self, msg: &str) {
+		let logger = &self.logger;
+		if let Some(msg) = msg.strip_prefix("[E] ") {
+			error!(logger, "{}", msg);
+		} else if let Some(msg) = msg.strip_prefix("[W] ") {
+			match self.stream {
+				MessageStream::Stdout => print_message(format!("warning: {}", msg)),
+				MessageStream::Stderr => warn!(logger, "{}", msg),
+			}
+		} else {
+			match self.stream {
+				MessageStream::Stdout => print_message(msg.to_owned()),
+				MessageStream::Stderr => eprintln!("{}", msg),
+			}
+		}
+	}
+}
+
+async fn handle_control_connection(
+	mut stream: TcpStream, session: &Session<'_>, tap: Tap<'_>, logger: Logger,
+) -> Result<(), OkcError> {
+	let offered = session.options.capabilities();
+	info!(logger, "control connection established"; "offered_capabilities" => format!("{:#x}", offered));
+	let start = Instant::now();
+	let mut accepted = None;
+	let mut first = true;
+	let mut versioned = false;
+	loop {
+		let msg = read_message(&mut stream).await?;
+		tap.str(&msg);
+		debug!(logger, "new warning message received"; "length" => msg.len());
+		if msg.is_empty() {
+			break;
+		}
+		let version = msg.strip_prefix(VERSION_PREFIX).filter(|_| first);
+		first = false;
+		if let Some(version) = version {
+			let version = version.trim().parse::<u8>()
+				.map_err(|_| OkcError::protocol(format!("invalid version message {:?}", msg)))?;
+			if version != PROTOCOL_VERSION {
+				let msg = format!("the app speaks protocol version {} but okc-gpg speaks {}, update the older one",
+					version, PROTOCOL_VERSION);
+				if session.options.strict_version {
+					return Err(OkcError::protocol(msg));
+				}
+				warn!(logger, "{}", msg);
+			} else {
+				debug!(logger, "app speaks protocol version {}", version);
+			}
+			versioned = true;
+		} else if let Some(line) = msg.strip_prefix(STATUS_PREFIX).filter(|_| versioned) {
+			match session.options.status_fd {
+				Some(ref dest) => write_status(dest, line)?,
+				None => debug!(logger, "dropping a status line, {} isn't set", STATUS_FD_ENV; "line" => line),
+			}
+		} else if let Some(checksum) = msg.strip_prefix(CHECKSUM_PREFIX).filter(|_| session.options.checksum) {
+			session.verify_checksum(checksum, &logger)?;
+		} else if let Some(filename) = msg.strip_prefix(FILENAME_PREFIX) {
+			info!(logger, "app suggested the output filename {:?}", filename);
+			*session.stats.suggested_filename.lock().unwrap() = Some(filename.to_owned());
+		} else if let Some(caps) = msg.strip_prefix(CAPABILITIES_PREFIX) {
+			let caps = caps.trim().parse::<i32>()
+				.map_err(|_| OkcError::protocol(format!("invalid capabilities message {:?}", msg)))?;
+			info!(logger, "app accepted capabilities {:#x}", caps; "offered_capabilities" => format!("{:#x}", offered));
+			if caps & !offered != 0 {
+				warn!(logger, "app claims capabilities that weren't offered: {:#x}", caps & !offered);
+			}
+			accepted = Some(caps);
+		} else {
+			match session.options.warning_sink {
+				Some(ref sink) => sink.warning(&msg),
+				None => LogSink { logger: logger.clone(), stream: session.options.app_messages }.warning(&msg),
+			}
+			session.stats.warnings.lock().unwrap().push(msg);
+		}
+	}
+	if accepted.is_none() && offered != 0 {
+		info!(logger, "app did not report its capabilities, it may only support the legacy protocol";
+			"offered_capabilities" => format!("{:#x}", offered));
+	}
+	debug!(logger, "all messages processed, waiting for status code");
+	let stat = read_byte(&mut stream).await?;
+	session.stats.phases.lock().unwrap().status_wait = Some(start.elapsed());
+	tap.status(stat);
+	info!(logger, "control connection finished"; "status_code" => stat);
+	match stat {
+		0 => Ok(()),
+		_ => Err(OkcError::App(stat)),
+	}
+}
+
+fn check_stdio_path(path: &str, role: Role) -> Result<(), OkcError> {
+	if path == "-" && !role.allows_stdio() {
+		return Err(OkcError::protocol(format!("stdio is not allowed for {} connections", role.name())));
+	}
+	Ok(())
+}
+
+/// The part of an input file the app asked for with [`OP_FLAG_RANGE`].
+#[derive(Clone, Copy, Debug)]
+struct Range {
+	offset: u64,
+	len: u64,
+}
+
+async fn send_input(
+	stream: &mut TcpStream, path: &str, range: Option<Range>, session: &Session<'_>, role: Role,
+	transfer: Transfer<'_>, logger: &Logger,
+) -> Result<Copied, OkcError> {
+	check_stdio_path(path, role)?;
+	// Auxiliary inputs like keyrings are binary, only the data itself is normalized.
+	let text_mode = session.options.text_mode.filter(|_| role == Role::Input);
+	if let Some(range) = range {
+		if path == "-" {
+			return Err(OkcError::protocol("a range was requested for stdin, which can't seek"));
+		}
+		let mut file = session.open_input(path).await?;
+		debug!(logger, "reading a range of the file"; "offset" => range.offset, "length" => range.len);
+		file.seek(io::SeekFrom::Start(range.offset)).await?;
+		stream_input(&mut file.take(range.len), stream, transfer, text_mode, logger).await
+	} else if let Some(file) = session.input_file.as_deref().filter(|_| path == "-" && role == Role::Input) {
+		let mut file = session.open_input(file).await?;
+		debug!(logger, "reading the input argument instead of stdin");
+		stream_input(&mut file, stream, transfer, text_mode, logger).await
+	} else if path == "-" {
+		if STDIN_USED.swap(true, Ordering::SeqCst) {
+			return Err(OkcError::protocol("stdin has already been read, it can only be streamed once"));
+		}
+		session.stats.stdin_read.store(true, Ordering::SeqCst);
+		let mut stdin = io::stdin();
+		debug!(logger, "reading from stdin");
+		stream_input(&mut stdin, stream, transfer, text_mode, logger).await
+	} else {
+		let mut file = session.open_input(path).await?;
+		let metadata = file.metadata().await?;
+		if metadata.is_file() && metadata.len() <= transfer.limits.small_file_size {
+			let mut data = Vec::with_capacity(metadata.len() as usize);
+			file.read_to_end(&mut data).await?;
+			debug!(logger, "read the file into memory"; "bytes" => data.len());
+			// A chunk larger than the data gets it all in one read, write_frames still splits it into frames.
+			let limits = Limits { chunk_size: data.len() + 1, ..transfer.limits.clone() };
+			return stream_input(&mut &data[..], stream, Transfer { limits: &limits, ..transfer }, text_mode, logger).await;
+		}
+		debug!(logger, "reading from file");
+		stream_input(&mut file, stream, transfer, text_mode, logger).await
+	}
+}
+
+async fn stream_input(
+	rx: &mut (impl AsyncRead + Unpin), stream: &mut TcpStream,
+	transfer: Transfer<'_>, text_mode: Option<LineEnding>, logger: &Logger,
+) -> Result<Copied, OkcError> {
+	if transfer.pull {
+		let (mut credits, mut tx) = stream.split();
+		copy_input(rx, &mut tx, Some(&mut credits), transfer, text_mode, logger).await
+	} else {
+		copy_input(rx, stream, None, transfer, text_mode, logger).await
+	}
+}
+
+async fn handle_input_connection(
+	mut stream: TcpStream, session: &Session<'_>, role: Role, transfer: Transfer<'_>, ranged: bool, logger: Logger,
+) -> Result<(), OkcError> {
+	let path = read_str(&mut stream).await?;
+	transfer.tap.str(&path);
+	let range = if ranged {
+		let range = Range { offset: read_u64(&mut stream).await?, len: read_u64(&mut stream).await? };
+		transfer.tap.range(range.offset, range.len);
+		Some(range)
+	} else {
+		None
+	};
+	info!(logger, "input connection established"; "path" => &path, "role" => role.name(),
+		"compressed" => transfer.compressed, "range" => ?range, "pull" => transfer.pull);
+	let res = send_input(&mut stream, &path, range, session, role, transfer, &logger).await;
+	if let Err(ref e) = res {
+		if is_connection_drop(e) {
+			// The app may stop reading once it doesn't need the rest, the control connection tells if it failed.
+			debug!(logger, "the app closed the input connection early: {}", e);
+			return Ok(());
+		}
+		// Closing normally would look like the app's own read failing halfway, a reset tells it that okc-gpg
+		// gave up so it can abort the operation.
+		debug!(logger, "resetting the input connection");
+		stream.set_linger(Some(Duration::from_secs(0)))?;
+	}
+	let copied = res?;
+	session.stats.input_bytes.fetch_add(copied.len, Ordering::SeqCst);
+	session.add_file(role.name(), &path, copied.len);
+	session.add_checksum(role, path, copied, &logger)?;
+	info!(logger, "input connection finished"; "bytes" => copied.len);
+	Ok(())
+}
+
+/// Substitutes the original filename into `template`. Only the last path component is used, so the data
+/// can't choose where it is written.
+fn apply_output_template(template: &str, filename: &str) -> Result<String, OkcError> {
+	let name = std::path::Path::new(filename).file_name().and_then(|name| name.to_str())
+		.filter(|name| *name != "." && *name != ".." && *name != "-")
+		.ok_or_else(|| OkcError::protocol(format!("invalid original filename {:?}", filename)))?;
+	Ok(template.replace("%f", name))
+}
+
+async fn open_extra_outputs(paths: &[String], primary: &str) -> Result<Vec<(String, Sink)>, OkcError> {
+	let mut extras = Vec::with_capacity(paths.len());
+	for path in paths {
+		if path == primary || (path == "-" && extras.iter().any(|(p, _)| p == "-")) {
+			return Err(OkcError::Other(format!("{} lists {:?} more than once", OUTPUT_EXTRA_ENV, path)));
+		}
+		let sink: Sink = if path == "-" {
+			Box::new(io::stdout())
+		} else {
+			Box::new(File::create(path).await
+				.map_err(|e| OkcError::Other(format!("failed to create extra output {:?}: {}", path, e)))?)
+		};
+		extras.push((path.clone(), sink));
+	}
+	Ok(extras)
+}
+
+/// Copies the output to `tx` and the extra outputs.
+async fn fan_out(
+	rx: &mut TcpStream, tx: &mut (impl AsyncWrite + Unpin), extras: Vec<(String, Sink)>,
+	dest: &str, transfer: Transfer<'_>, logger: &Logger,
+) -> Result<Copied, OkcError> {
+	if extras.is_empty() {
+		return copy_output(rx, tx, dest, transfer, logger).await;
+	}
+	let mut tx = FanOut::new(tx, extras);
+	let res = copy_output(rx, &mut tx, dest, transfer, logger).await;
+	res.map_err(|e| tx.failed.take().unwrap_or(e))
+}
+
+async fn handle_output_connection(
+	mut stream: TcpStream, session: &Session<'_>, role: Role, transfer: Transfer<'_>, named: bool, with_metadata: bool,
+	logger: Logger,
+) -> Result<(), OkcError> {
+	let requested_path = read_str(&mut stream).await?;
+	transfer.tap.str(&requested_path);
+	let mut path = requested_path.clone();
+	if named {
+		let filename = read_str(&mut stream).await?;
+		transfer.tap.str(&filename);
+		match session.options.output_template {
+			Some(ref template) if !filename.is_empty() && role == Role::Output => {
+				let templated = apply_output_template(template, &filename)?;
+				debug!(logger, "naming the output after the original filename";
+					"filename" => &filename, "requested_path" => &path);
+				path = templated;
+			}
+			_ => debug!(logger, "not using the original filename"; "filename" => &filename),
+		}
+	}
+	let metadata = if with_metadata {
+		let metadata = FileMetadata { mode: read_u32(&mut stream).await?, mtime_ms: read_u64(&mut stream).await? };
+		transfer.tap.metadata(metadata.mode, metadata.mtime_ms);
+		Some(metadata)
+	} else {
+		None
+	};
+	info!(logger, "output connection established";
+		"path" => &path, "role" => role.name(), "compressed" => transfer.compressed);
+	check_stdio_path(&path, role)?;
+	let extras = if role == Role::Output { open_extra_outputs(&session.options.output_extra, &path).await? } else { Vec::new() };
+	let extra_paths = extras.iter().map(|(path, _)| path.clone()).collect::<Vec<_>>();
+	let _flush_guard = begin_flush().await;
+	let _stdout_guard = (path == "-" || extra_paths.iter().any(|path| path == "-")).then(begin_stdout);
+	session.output_started.store(true, Ordering::SeqCst);
+	let copied = if &path == "-" {
+		let mut stdout = io::stdout();
+		debug!(logger, "writing to stdout");
+		let copied = fan_out(&mut stream, &mut stdout, extras, "stdout", transfer, &logger).await?;
+		stdout.flush().await.map_err(|e| write_error(e, "stdout"))?;
+		copied
+	} else {
+		let mut file = session.create_output(&path).await?;
+		debug!(logger, "writing to file");
+		let copied = fan_out(&mut stream, &mut file, extras, &path, transfer, &logger).await?;
+		file.flush().await.map_err(|e| write_error(e, &path))?;
+		if let Some(metadata) = metadata {
+			metadata.apply(&file, &path, &logger).await?;
+		}
+		if session.options.verify_output {
+			session.verify_output(&path, copied.len, &logger).await?;
+		}
+		copied
+	};
+	session.stats.output_bytes.fetch_add(copied.len, Ordering::SeqCst);
+	session.add_file(role.name(), &path, copied.len);
+	for extra in extra_paths {
+		// The extras aren't resolved against the working directory, unlike the paths from the app.
+		let extra = match absolute(Path::new(&extra)) {
+			Ok(absolute) if extra != "-" => absolute.to_string_lossy().into_owned(),
+			_ => extra,
+		};
+		session.stats.files.lock().unwrap().push(TouchedFile { role: "extra", path: extra, bytes: copied.len });
+	}
+	session.add_checksum(role, requested_path, copied, &logger)?;
+	info!(logger, "output connection finished"; "bytes" => copied.len);
+	Ok(())
+}
+
+/// The metadata the app sent for an output file with [`OP_FLAG_METADATA`], all ones meaning unset.
+#[derive(Clone, Copy, Debug)]
+struct FileMetadata {
+	mode: u32,
+	mtime_ms: u64,
+}
+
+impl FileMetadata {
+	/// Sets the permissions and modification time of `file`. FIFOs and devices are left alone.
+	async fn apply(self, file: &File, path: &str, logger: &Logger) -> Result<(), OkcError> {
+		use std::os::unix::fs::PermissionsExt;
+		use std::os::unix::io::AsRawFd;
+		if !file.metadata().await?.is_file() {
+			debug!(logger, "not applying metadata to output that isn't a regular file"; "path" => path);
+			return Ok(());
+		}
+		let failed = |e: io::Error, what| OkcError::Other(format!("failed to set the {} of {:?}: {}", what, path, e));
+		if self.mode != u32::MAX {
+			// Only the permission bits, the app has no business making files setuid.
+			let mode = self.mode & 0o777;
+			debug!(logger, "setting the mode of the output to {:o}", mode; "path" => path);
+			file.set_permissions(std::fs::Permissions::from_mode(mode)).await.map_err(|e| failed(e, "mode"))?;
+		}
+		if self.mtime_ms != u64::MAX {
+			debug!(logger, "setting the modification time of the output"; "path" => path, "mtime_ms" => self.mtime_ms);
+			let omit = libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT };
+			let mtime = libc::timespec {
+				tv_sec: (self.mtime_ms / 1000) as libc::time_t,
+				tv_nsec: (self.mtime_ms % 1000 * 1_000_000) as libc::c_long,
+			};
+			if unsafe { libc::futimens(file.as_raw_fd(), [omit, mtime].as_ptr()) } != 0 {
+				return Err(failed(io::Error::last_os_error(), "modification time"));
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Handles a data connection of type `op`, whose flags have been checked against the options already.
+async fn handle_data_connection(
+	stream: TcpStream, session: &Session<'_>, role: Role, transfer: Transfer<'_>, op: u8, logger: Logger,
+) -> Result<(), OkcError> {
+	let named = op & OP_FLAG_FILENAME != 0;
+	let ranged = op & OP_FLAG_RANGE != 0;
+	let with_metadata = op & OP_FLAG_METADATA != 0;
+	let work = async {
+		if role.is_read() {
+			if named {
+				return Err(OkcError::protocol("original filename sent for input connection"));
+			}
+			if with_metadata {
+				return Err(OkcError::protocol("file metadata sent for input connection"));
+			}
+			handle_input_connection(stream, session, role, transfer, ranged, logger).await
+		} else {
+			if ranged {
+				return Err(OkcError::protocol("range requested for output connection"));
+			}
+			if transfer.pull {
+				return Err(OkcError::protocol("pull requested for output connection"));
+			}
+			handle_output_connection(stream, session, role, transfer, named, with_metadata, logger).await
+		}
+	};
+	let (id, start) = (transfer.tap.id, Instant::now());
+	let res = match transfer.limits.max_connection_duration {
+		Some(limit) => time::timeout(limit, work).await.unwrap_or_else(|_| Err(OkcError::Timeout(format!(
+			"{} connection {} took longer than the limit of {} seconds set by {}",
+			role.name(), id, limit.as_secs(), MAX_CONNECTION_SECS_ENV,
+		)))),
+		None => work.await,
+	};
+	let timing = ConnectionTiming { id, role: role.name(), duration: start.elapsed() };
+	session.stats.phases.lock().unwrap().connections.push(timing);
+	res
+}
+
+/// Reads the op byte and, for tagged connections, the role tag.
+async fn read_handshake(stream: &mut TcpStream) -> Result<(u8, Option<u8>), OkcError> {
+	let op = read_byte(stream).await?;
+	let tag = if op & !OP_FLAGS == OP_TAGGED { Some(read_byte(stream).await?) } else { None };
+	Ok((op, tag))
+}
+
+/// Handles one accepted connection. Returns `true` once the control connection has reported success,
+/// errors on data connections are only logged.
+async fn handle_connection(
+	mut stream: TcpStream, id: u64, session: &Session<'_>, logger: Logger,
+) -> Result<bool, OkcError> {
+	// The peer address is only needed to tell connections apart in the logs, not worth failing over.
+	let logger = match stream.peer_addr() {
+		Ok(addr) => logger.new(o!("remote_port" => addr.port())),
+		Err(e) => {
+			let logger = logger.new(o!("connection" => id));
+			debug!(logger, "failed to get the peer address: {}", e);
+			logger
+		}
+	};
+	debug!(logger, "connection accepted");
+	let timeout = session.options.limits.handshake_timeout;
+	let (op, tag) = match time::timeout(timeout, read_handshake(&mut stream)).await {
+		Ok(res) => res?,
+		Err(_) => {
+			warn!(logger, "no connection type received within {:?}, dropping the connection", timeout);
+			return Ok(false);
+		}
+	};
+	debug!(logger, "connection type is {}", op);
+	let tap = Tap { recorder: session.recorder.as_ref(), id };
+	tap.connect(op, tag);
+	let res = dispatch(stream, op, tag, session, tap, &logger).await;
+	tap.close();
+	res
+}
+
+async fn dispatch(
+	stream: TcpStream, op: u8, tag: Option<u8>, session: &Session<'_>, tap: Tap<'_>, logger: &Logger,
+) -> Result<bool, OkcError> {
+	let compressed = op & OP_FLAG_COMPRESSED != 0;
+	let named = op & OP_FLAG_FILENAME != 0;
+	let ranged = op & OP_FLAG_RANGE != 0;
+	let pull = op & OP_FLAG_PULL != 0;
+	let with_metadata = op & OP_FLAG_METADATA != 0;
+	let data = matches!(op & !OP_FLAGS, OP_INPUT | OP_OUTPUT | OP_TAGGED);
+	let transfer = Transfer {
+		limits: &session.options.limits, compressed, checksum: session.options.checksum, pull, tap,
+		used: Some(&session.transferred),
+	};
+	let res = match op & !OP_FLAGS {
+		_ if compressed && !session.options.compression =>
+			Err(OkcError::protocol("compression requested but not offered")),
+		_ if named && session.options.output_template.is_none() =>
+			Err(OkcError::protocol("original filename sent but not requested")),
+		_ if ranged && !session.options.input_ranges =>
+			Err(OkcError::protocol("input range requested but not offered")),
+		_ if pull && !session.options.input_pull =>
+			Err(OkcError::protocol("input pull requested but not offered")),
+		_ if with_metadata && !session.options.output_metadata =>
+			Err(OkcError::protocol("file metadata sent but not offered")),
+		OP_CONTROL if compressed || named || ranged || pull || with_metadata =>
+			Err(OkcError::protocol("flags set for control connection")),
+		OP_CONTROL if session.control_seen.swap(true, Ordering::SeqCst) =>
+			Err(OkcError::protocol("duplicate control connection")),
+		OP_CONTROL => {
+			handle_control_connection(stream, session, tap, logger.clone()).await?;
+			session.succeeded.store(true, Ordering::SeqCst);
+			return Ok(true);
+		}
+		OP_INPUT => handle_data_connection(stream, session, Role::Input, transfer, op, logger.clone()).await,
+		OP_OUTPUT => handle_data_connection(stream, session, Role::Output, transfer, op, logger.clone()).await,
+		OP_TAGGED => match tag.and_then(Role::from_tag) {
+			Some(role) => handle_data_connection(stream, session, role, transfer, op, logger.clone()).await,
+			None => Err(OkcError::protocol("invalid connection role")),
+		},
+		_ => Err(OkcError::protocol("invalid connection type")),
+	};
+	match res {
+		// The app can't take the failure into account any more. Timeouts and protocol errors stay what they
+		// are, since the exit status tells them apart.
+		Err(e) if session.succeeded.load(Ordering::SeqCst) => {
+			let after = |e: &dyn std::fmt::Display| format!("a data connection failed after the app reported success: {}", e);
+			Err(match e {
+				OkcError::Timeout(s) => OkcError::Timeout(after(&s)),
+				OkcError::Protocol(s) => OkcError::Protocol(after(&s)),
+				e => OkcError::Other(after(&e)),
+			})
+		}
+		Err(e) => {
+			error!(logger, "{:?}", e);
+			if is_connection_drop(&e) {
+				session.data_dropped.store(true, Ordering::SeqCst);
+			}
+			if data {
+				session.data_error.lock().unwrap().get_or_insert(e);
+			}
+			Ok(false)
+		}
+		Ok(()) => Ok(false),
+	}
+}
+
+/// Runs one operation: sends the broadcast and serves the app's connections until the control
+/// connection finishes. The app's status code is reported as [`OkcError::App`].
+pub async fn run(broadcaster: &dyn Broadcaster, options: &Options, args: &[String], logger: Logger) -> Result<(), OkcError> {
+	run_with_stats(broadcaster, options, args, &Stats::default(), logger).await
+}
+
+/// Like [`run`], but adds the transferred byte counts to `stats`.
+pub async fn run_with_stats(
+	broadcaster: &dyn Broadcaster, options: &Options, args: &[String], stats: &Stats, logger: Logger,
+) -> Result<(), OkcError> {
+	broadcaster.check().await?;
+	let translated;
+	let args = match options.gpg_profile {
+		Some(profile) => {
+			let (args, changes) = translate(args, profile);
+			for change in changes {
+				match change {
+					Change::Dropped(dropped) => info!(logger, "dropping unsupported arguments {:?}", dropped),
+					Change::Renamed(old, new) => info!(logger, "rewriting argument {:?} to {:?}", old, new),
+				}
+			}
+			translated = args;
+			&translated[..]
+		}
+		None => args,
+	};
+	let replaced;
+	let (args, input_file) = match options.input_arg {
+		Some(index) => {
+			let (args, path) = take_input_arg(args, index)
+				.map_err(|e| OkcError::Other(format!("invalid value for {}: {}", INPUT_ARG_ENV, e)))?;
+			debug!(logger, "streaming argument {} as stdin", index; "path" => &path);
+			replaced = args;
+			(&replaced[..], Some(path))
+		}
+		None => (args, None),
+	};
+	let listener = bind_loopback(options.bind_port).await.map_err(|e| match e.kind() {
+		io::ErrorKind::AddrInUse => OkcError::Other(format!(
+			"port {} is already in use, possibly by another okc-gpg, choose another one with {} or unset it",
+			options.bind_port, BIND_PORT_ENV,
+		)),
+		_ => OkcError::Io(e),
+	})?;
+	let port = listener.local_addr()?.port();
+	info!(logger, "listening on {}", listener.local_addr()?);
+	// The broadcast must only be sent from here on: the app connects as soon as it gets it, and connections
+	// queue up in the backlog until `serve` accepts them.
+	let res = serve(listener, broadcaster, options, args, input_file, stats, &logger).await;
+	broadcaster.cleanup(port).await;
+	res
+}
+
+async fn serve(
+	listener: TcpListener, broadcaster: &dyn Broadcaster, options: &Options, args: &[String],
+	input_file: Option<String>, stats: &Stats, logger: &Logger,
+) -> Result<(), OkcError> {
+	let addr = listener.local_addr()?;
+	let port = addr.port();
+	let start = Instant::now();
+	broadcaster.send(port, args).await?;
+	let sent = Instant::now();
+	stats.phases.lock().unwrap().broadcast = Some(sent - start);
+	info!(logger, "broadcast sent, waiting for app to connect"; "address" => %addr);
+	// Cleared once the app has connected or the fallback has been tried.
+	let mut fallback_at = Some(time::Instant::now() + FALLBACK_DELAY);
+	// Cleared once the app has connected or the warning has been shown.
+	let mut warning_at = Some(time::Instant::now() + CONNECT_WARNING_DELAY);
+
+	let recorder = match options.record {
+		Some(ref path) => {
+			let recorder = Recorder::create(path, options.record_data, logger.clone())?;
+			recorder.broadcast(listener.local_addr()?.port(), args);
+			Some(recorder)
+		}
+		None => None,
+	};
+	let session = Session {
+		options, control_seen: AtomicBool::new(false), succeeded: AtomicBool::new(false), transferred: AtomicU64::new(0),
+		stats, recorder, checksums: Mutex::new(Vec::new()),
+		pending_checksums: Mutex::new(Vec::new()), output_started: AtomicBool::new(false),
+		data_dropped: AtomicBool::new(false), data_error: Mutex::new(None), input_file,
+	};
+	let permits = Semaphore::new(options.limits.max_connections);
+	let mut connections = FuturesUnordered::new();
+	// When the app reported success, see the module docs for what happens afterwards.
+	let mut reported: Option<time::Instant> = None;
+	let mut resumes_left = options.retry.resumes;
+	// Whether the control connection was dropped, and when to broadcast again, see [`RESUME_ENV`].
+	let mut control_dropped = false;
+	let mut resume_at: Option<time::Instant> = None;
+	// Unlike the stats, not reset on a resume so that the recording tells the connections apart.
+	let mut last_id = 0;
+	loop {
+		let accepting = match reported {
+			Some(at) => at.elapsed() < BACKLOG_GRACE,
+			None => true,
+		};
+		if !accepting && connections.is_empty() {
+			return match session.data_error.lock().unwrap().take() {
+				Some(e) => Err(OkcError::Other(format!("a data connection failed before the app reported success: {}", e))),
+				None => session.check_pending_checksums(),
+			};
+		}
+		let wake = match reported {
+			Some(at) if accepting => Some(at + BACKLOG_GRACE),
+			Some(at) if !options.wait_output => Some(at + DRAIN_TIMEOUT),
+			_ => None,
+		};
+		tokio::select! {
+			accept_result = listener.accept(), if accepting => {
+				debug!(logger, "new incoming connection");
+				let (stream, _) = accept_result?;
+				stats.phases.lock().unwrap().first_connection.get_or_insert_with(|| sent.elapsed());
+				fallback_at = None;
+				warning_at = None;
+				if !control_dropped {
+					resume_at = None;
+				}
+				stats.connections.fetch_add(1, Ordering::SeqCst);
+				last_id += 1;
+				let id = last_id;
+				let (session, permits, logger) = (&session, &permits, logger.clone());
+				connections.push(async move {
+					let _permit = permits.acquire().await.map_err(|e| OkcError::Other(e.to_string()))?;
+					handle_connection(stream, id, session, logger).await
+				});
+			}
+			Some(res) = connections.next() => {
+				match res {
+					Ok(true) => {
+						debug!(logger, "the app reported success"; "open_connections" => connections.len());
+						reported = Some(time::Instant::now());
+					}
+					Ok(false) => {}
+					Err(e) if resumes_left > 0 && is_connection_drop(&e) => {
+						debug!(logger, "the control connection was dropped: {}", e; "open_connections" => connections.len());
+						control_dropped = true;
+					}
+					Err(e) => return Err(e),
+				}
+				let dropped = control_dropped || session.data_dropped.load(Ordering::SeqCst);
+				if resumes_left > 0 && reported.is_none() && dropped && connections.is_empty() {
+					let wait = if control_dropped { Duration::ZERO } else { RESUME_DELAY };
+					let backoff = options.retry.backoff.delay(options.retry.resumes - resumes_left + 1);
+					resume_at = Some(time::Instant::now() + wait.max(backoff));
+				}
+			}
+			_ = time::sleep_until(resume_at.unwrap_or_else(time::Instant::now)), if resume_at.is_some() => {
+				resume_at = None;
+				if !session.can_resume() {
+					return Err(OkcError::Other(
+						"the app dropped its connections before reporting a status, and the operation can't be resumed \
+						as output has already been written or stdin read".to_owned()
+					));
+				}
+				resumes_left -= 1;
+				warn!(logger, "the app dropped its connections before reporting a status, broadcasting again";
+					"resumes_left" => resumes_left);
+				control_dropped = false;
+				session.reset();
+				broadcaster.send(port, args).await?;
+				warning_at = Some(time::Instant::now() + CONNECT_WARNING_DELAY);
+			}
+			_ = time::sleep_until(fallback_at.unwrap_or_else(time::Instant::now)), if fallback_at.is_some() => {
+				fallback_at = None;
+				if broadcaster.fallback(port, args).await? {
+					info!(logger, "waiting for app to connect");
+				}
+			}
+			_ = time::sleep_until(warning_at.unwrap_or_else(time::Instant::now)), if warning_at.is_some() => {
+				warning_at = None;
+				// The app can't tell okc-gpg that its connection was refused, only the silence is visible here.
+				warn!(logger, "no connection from the app has reached {} within {} seconds", addr, CONNECT_WARNING_DELAY.as_secs());
+				warn!(logger, "if OkcAgent reports a connection error, check for firewall rules blocking loopback \
+					connections to port {}, e.g. with `iptables -S`", port);
+			}
+			_ = time::sleep_until(wake.unwrap_or_else(time::Instant::now)), if wake.is_some() => {
+				if accepting {
+					if !connections.is_empty() {
+						info!(logger, "waiting for {} data connections to finish", connections.len());
+					}
+				} else {
+					return Err(OkcError::Timeout(format!(
+						"{} data connections still open {} seconds after the app reported success, set {}=1 to wait for them",
+						connections.len(), DRAIN_TIMEOUT.as_secs(), WAIT_OUTPUT_ENV,
+					)));
+				}
+			}
+		}
+	}
+}
+
+/// Whether the app closed or reset a connection halfway, as it does when it's killed.
+fn is_connection_drop(e: &OkcError) -> bool {
+	match e {
+		OkcError::Io(e) => matches!(e.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+			| io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe),
+		_ => false,
+	}
+}
+
+/// Spawns the `OKC_ON_SUCCESS` or `OKC_ON_FAILURE` command for the outcome `res` and waits for it. The
+/// command finds the results in `OKC_STATUS` (the app's status code, unset if the app never reported one),
+/// `OKC_ERROR`, `OKC_INPUT_BYTES` and `OKC_OUTPUT_BYTES`.
+async fn run_hook(options: &Options, res: &Result<(), OkcError>, stats: &Stats, logger: &Logger) {
+	let hook = match (res, &options.on_success, &options.on_failure) {
+		(Ok(_), Some(hook), _) | (Err(_), _, Some(hook)) => hook,
+		_ => return,
+	};
+	debug!(logger, "running hook"; "command" => hook);
+	let mut cmd = Command::new("sh");
+	cmd.arg("-c").arg(hook)
+		.env("OKC_INPUT_BYTES", stats.input_bytes.load(Ordering::SeqCst).to_string())
+		.env("OKC_OUTPUT_BYTES", stats.output_bytes.load(Ordering::SeqCst).to_string())
+		.stdin(Stdio::null()).stdout(Stdio::null());
+	match res {
+		Ok(_) => { cmd.env("OKC_STATUS", "0"); }
+		Err(e) => {
+			if let OkcError::App(status) = e {
+				cmd.env("OKC_STATUS", status.to_string());
+			}
+			cmd.env("OKC_ERROR", e.to_string());
+		}
+	}
+	match cmd.status().await {
+		Ok(status) if status.success() => {}
+		Ok(status) => warn!(logger, "hook failed"; "command" => hook, "status" => %status),
+		Err(e) => warn!(logger, "failed to run hook: {}", e; "command" => hook),
+	}
+}
+
+/// The app's status code for the outcome `res`, if it reported one.
+fn status_code(res: &Result<(), OkcError>) -> Option<u8> {
+	match res {
+		Ok(_) => Some(0),
+		Err(OkcError::App(status)) => Some(*status),
+		Err(_) => None,
+	}
+}
+
+/// Starts the JSON summary of the outcome `res` with the fields shared by operations and batches.
+fn outcome_json(res: &Result<(), OkcError>) -> json::Object {
+	json::Object::new()
+		.raw("success", res.is_ok())
+		.opt("status", status_code(res))
+		.opt("error", res.as_ref().err().map(|e| json::string(&e.to_string())))
+}
+
+/// Summarizes the outcome of an operation as JSON, see [`Options::result_json`].
+fn result_json(res: &Result<(), OkcError>, stats: &Stats, elapsed: Duration, attempts: u32) -> String {
+	let warnings = stats.warnings.lock().unwrap();
+	outcome_json(res)
+		.raw("input_bytes", stats.input_bytes.load(Ordering::SeqCst))
+		.raw("output_bytes", stats.output_bytes.load(Ordering::SeqCst))
+		.raw("elapsed_ms", elapsed.as_millis())
+		.raw("connections", stats.connections.load(Ordering::SeqCst))
+		.raw("attempts", attempts)
+		.raw("warnings", json::array(warnings.iter().map(|msg| json::string(msg))))
+		.raw("outputs", json::array(stats.outputs.lock().unwrap().iter().map(OutputFile::to_json)))
+		.opt("suggested_filename", stats.suggested_filename.lock().unwrap().as_deref().map(json::string))
+		.raw("phases", stats.phases.lock().unwrap().to_json())
+		.finish()
+}
+
+/// Writes a JSON summary to `dest`, which is either a path or a file descriptor number.
+fn write_result(dest: &str, result: &str) -> io::Result<()> {
+	use std::io::Write;
+	writeln!(open_report(dest)?, "{}", result)
+}
+
+/// Appends a status line to `dest`, which is either a path or a file descriptor number that is left open.
+fn write_status(dest: &str, line: &str) -> io::Result<()> {
+	use std::io::Write;
+	use std::os::unix::io::FromRawFd;
+	match dest.parse::<i32>() {
+		Ok(fd) => writeln!(std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) }), "{}", line),
+		Err(_) => writeln!(std::fs::OpenOptions::new().create(true).append(true).open(dest)?, "{}", line),
+	}
+}
+
+/// Opens `dest` for machine-readable output, which is either a path or a file descriptor number. The
+/// descriptor is duplicated, so that closing the file leaves it open for the other reports written to it.
+fn open_report(dest: &str) -> io::Result<std::fs::File> {
+	use std::os::unix::io::FromRawFd;
+	match dest.parse::<i32>() {
+		Ok(fd) => match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } {
+			-1 => Err(io::Error::last_os_error()),
+			dup => Ok(unsafe { std::fs::File::from_raw_fd(dup) }),
+		},
+		Err(_) => std::fs::File::create(dest),
+	}
+}
+
+/// Doesn't broadcast at all, but writes the port to `dest` (see [`LISTEN_ONLY_ENV`]) so a controller can send
+/// the broadcast whenever it wants to. A file descriptor gets a line for every broadcast, while a file is
+/// rewritten with one line for each operation still listening, in the order they started.
+pub struct ListenOnlyBroadcaster {
+	dest: String,
+	logger: Logger,
+	ports: Mutex<Vec<u16>>,
+}
+
+impl ListenOnlyBroadcaster {
+	pub fn new(dest: String, logger: Logger) -> Self {
+		ListenOnlyBroadcaster { dest, logger, ports: Mutex::new(Vec::new()) }
+	}
+}
+
+impl Broadcaster for ListenOnlyBroadcaster {
+	fn send<'a>(&'a self, port: u16, _args: &'a [String]) -> BoxFuture<'a, Result<(), OkcError>> {
+		use std::io::Write;
+		Box::pin(async move {
+			info!(self.logger, "not sending the broadcast, writing the port to {}", self.dest);
+			let mut ports = self.ports.lock().unwrap();
+			if !ports.contains(&port) {
+				ports.push(port);
+			}
+			let mut file = open_report(&self.dest)?;
+			if self.dest.parse::<i32>().is_ok() {
+				writeln!(file, "{}", port)?;
+			} else {
+				for port in ports.iter() {
+					writeln!(file, "{}", port)?;
+				}
+			}
+			file.flush()?;
+			Ok(())
+		})
+	}
+
+	fn cleanup(&self, port: u16) -> BoxFuture<'_, ()> {
+		self.ports.lock().unwrap().retain(|&p| p != port);
+		Box::pin(async {})
+	}
+}
+
+/// Like [`run`], but starts over with a new broadcast when the app reports a status code that
+/// `options.retry` considers transient. Runs the success or failure hook once the final attempt is done.
+pub async fn run_with_retries(
+	broadcaster: &dyn Broadcaster, options: &Options, args: &[String], logger: Logger,
+) -> Result<(), OkcError> {
+	let attempts = run_attempts(broadcaster, options, args, logger.clone()).await;
+	if let Some(ref dest) = options.result_json {
+		if let Err(e) = write_result(dest, &attempts.result) {
+			warn!(logger, "failed to write the result to {}: {}", dest, e);
+		}
+	}
+	write_manifest(options, &attempts.files, &logger);
+	attempts.res
+}
+
+/// The outcome of an operation, with everything needed to report it.
+struct Attempts {
+	res: Result<(), OkcError>,
+	/// The files touched by all attempts.
+	files: Vec<TouchedFile>,
+	/// The JSON summary of the final attempt, see [`result_json`].
+	result: String,
+}
+
+/// Like [`run_with_retries`], but returns the reports instead of writing them.
+async fn run_attempts(broadcaster: &dyn Broadcaster, options: &Options, args: &[String], logger: Logger) -> Attempts {
+	let start = Instant::now();
+	let mut attempt = 1;
+	let mut files = Vec::new();
+	loop {
+		let logger = logger.new(o!("attempt" => attempt));
+		let stats = Stats::default();
+		let res = run_with_stats(broadcaster, options, args, &stats, logger.clone()).await;
+		files.append(&mut stats.files.lock().unwrap());
+		if let Err(OkcError::App(status)) = res {
+			if options.retry.is_retryable(status) && attempt <= options.retry.retries {
+				if !stats.stdin_read.load(Ordering::SeqCst) {
+					let delay = options.retry.backoff.delay(attempt);
+					warn!(logger, "the app reported a retryable error, retrying in {:?}", delay; "status_code" => status);
+					time::sleep(delay).await;
+					attempt += 1;
+					continue;
+				}
+				warn!(logger, "not retrying since stdin has already been consumed"; "status_code" => status);
+			}
+		}
+		info!(logger, "operation finished"; "success" => res.is_ok());
+		run_hook(options, &res, &stats, &logger).await;
+		let result = result_json(&res, &stats, start.elapsed(), attempt);
+		return Attempts { res, files, result };
+	}
+}
+
+/// Joins a relative `path` to the current directory, for the manifest.
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+	if path.is_absolute() {
+		Ok(path.to_owned())
+	} else {
+		Ok(std::env::current_dir()?.join(path))
+	}
+}
+
+/// Writes the files read and written by the operations to [`Options::manifest`] as JSON, if it is set.
+fn write_manifest(options: &Options, files: &[TouchedFile], logger: &Logger) {
+	use std::io::Write;
+	let dest = match options.manifest {
+		Some(ref dest) => dest,
+		None => return,
+	};
+	let manifest = json::Object::new().raw("files", json::array(files.iter().map(TouchedFile::to_json))).finish();
+	if let Err(e) = open_report(dest).and_then(|mut file| writeln!(file, "{}", manifest)) {
+		warn!(logger, "failed to write the manifest to {}: {}", dest, e);
+	}
+}
+
+/// Runs the operations of a batch concurrently, one broadcast and listener each, like [`run_with_retries`].
+/// Only the first operation asking for stdin gets it, the others fail with a protocol error. Fails with the
+/// outcome that has the highest exit status (see [`exit_code`]), the earliest one if several do. The result
+/// JSON has that outcome and the results of the operations in the order of the batch.
+pub async fn run_batch(
+	broadcaster: &dyn Broadcaster, options: &Options, batch: &[Vec<String>], logger: Logger,
+) -> Result<(), OkcError> {
+	if options.bind_port != 0 && batch.len() > 1 {
+		return Err(OkcError::Other(format!("{} can't be used for more than one concurrent operation", BIND_PORT_ENV)));
+	}
+	info!(logger, "running {} operations", batch.len());
+	let mut pending = batch.iter().enumerate().map(|(i, args)| {
+		let logger = logger.new(o!("operation" => i + 1));
+		async move { (i, run_attempts(broadcaster, options, args, logger).await) }
+	}).collect::<FuturesUnordered<_>>();
+	let mut slots = batch.iter().map(|_| None).collect::<Vec<Option<Attempts>>>();
+	while let Some((i, attempts)) = pending.next().await {
+		slots[i] = Some(attempts);
+	}
+	let results = slots.into_iter().map(|attempts| attempts.expect("every operation finishes")).collect::<Vec<_>>();
+	let files = results.iter().flat_map(|attempts| attempts.files.iter().cloned()).collect::<Vec<_>>();
+	let mut worst = Ok(());
+	let mut operations = Vec::with_capacity(results.len());
+	for (i, attempts) in results.into_iter().enumerate() {
+		operations.push(attempts.result);
+		match attempts.res {
+			Ok(()) => {}
+			Err(e) => {
+				warn!(logger, "operation {} failed: {}", i + 1, e; "args" => ?batch[i]);
+				worst = match worst {
+					Err(worst) if exit_code(&ExitReason::Error(&worst)) >= exit_code(&ExitReason::Error(&e)) => Err(worst),
+					_ => Err(e),
+				};
+			}
+		}
+	}
+	if let Some(ref dest) = options.result_json {
+		let result = outcome_json(&worst).raw("operations", json::array(operations)).finish();
+		if let Err(e) = write_result(dest, &result) {
+			warn!(logger, "failed to write the result to {}: {}", dest, e);
+		}
+	}
+	write_manifest(options, &files, &logger);
+	worst
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn package_is_added_to_class_names() {
+		assert_eq!(full_component(".GpgProxyReceiver"), "org.ddosolitary.okcagent/.GpgProxyReceiver");
+		assert_eq!(full_component("org.ddosolitary.okcagent.Receiver"), "org.ddosolitary.okcagent/org.ddosolitary.okcagent.Receiver");
+		assert_eq!(full_component("com.example/.Receiver"), "com.example/.Receiver");
+	}
+
+	#[test]
+	fn cached_receiver() {
+		let path = std::env::temp_dir().join(format!("okc-agents-receiver-cache-{}", std::process::id()));
+		std::fs::write(&path, "org.ddosolitary.okcagent/.NewReceiver\n").unwrap();
+		assert_eq!(read_cached_receiver(&path).as_deref(), Some("org.ddosolitary.okcagent/.NewReceiver"));
+		// Anything but a receiver of the app is ignored, as is a stale cache.
+		std::fs::write(&path, "com.example/.Receiver").unwrap();
+		assert_eq!(read_cached_receiver(&path), None);
+		std::fs::write(&path, "org.ddosolitary.okcagent/.NewReceiver").unwrap();
+		let stale = std::time::SystemTime::now() - RECEIVER_CACHE_AGE - Duration::from_secs(1);
+		let stale = stale.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as libc::time_t;
+		let times = [libc::timeval { tv_sec: stale, tv_usec: 0 }; 2];
+		let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+		assert_eq!(unsafe { libc::utimes(c_path.as_ptr(), times.as_ptr()) }, 0);
+		assert_eq!(read_cached_receiver(&path), None);
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(read_cached_receiver(&path), None);
+	}
+
+	#[test]
+	fn shell_quote_only_quotes_when_needed() {
+		assert_eq!(shell_quote("--es"), "--es");
+		assert_eq!(shell_quote("org.ddosolitary.okcagent/.GpgProxyReceiver"), "org.ddosolitary.okcagent/.GpgProxyReceiver");
+		assert_eq!(shell_quote("two words"), "'two words'");
+		assert_eq!(shell_quote("it's"), "'it'\\''s'");
+		assert_eq!(shell_quote(""), "''");
+		assert_eq!(shell_quote("$HOME"), "'$HOME'");
+	}
+
+	#[test]
+	fn shell_quote_survives_the_shell() {
+		for s in ["", "two  words", "it's", "\"quoted\"", "a\\b", "$(id) `id` ; | & *", "line\nbreak"] {
+			let output = std::process::Command::new("sh").arg("-c").arg(format!("printf %s {}", shell_quote(s)))
+				.output().unwrap();
+			assert_eq!(String::from_utf8(output.stdout).unwrap(), s);
+		}
+	}
+}
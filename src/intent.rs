@@ -0,0 +1,97 @@
+//! The extras of the intents okc-gpg sends to the app, rendered into `am` arguments.
+
+use crate::args::Extra;
+
+/// A typed extra value, each type having its own `am` option.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExtraValue {
+	String(String),
+	Int(i32),
+	Long(i64),
+	Float(f32),
+	Bool(bool),
+	StringArray(Vec<String>),
+}
+
+impl ExtraValue {
+	/// Parses `value` as the type of the `am` option `flag`, such as `--ei`.
+	pub fn parse(flag: &str, value: &str) -> Result<Self, String> {
+		let invalid = |kind| format!("invalid {} value {:?}", kind, value);
+		Ok(match flag {
+			"--es" => Self::String(value.to_owned()),
+			"--ei" => Self::Int(value.parse().map_err(|_| invalid("int"))?),
+			"--el" => Self::Long(value.parse().map_err(|_| invalid("long"))?),
+			"--ef" => Self::Float(value.parse().map_err(|_| invalid("float"))?),
+			"--ez" => Self::Bool(value.parse().map_err(|_| invalid("bool"))?),
+			_ => return Err(format!("unsupported extra type {}", flag)),
+		})
+	}
+
+	pub fn flag(&self) -> &'static str {
+		match self {
+			Self::String(_) => "--es",
+			Self::Int(_) => "--ei",
+			Self::Long(_) => "--el",
+			Self::Float(_) => "--ef",
+			Self::Bool(_) => "--ez",
+			Self::StringArray(_) => "--esa",
+		}
+	}
+
+	fn render(&self) -> String {
+		match self {
+			Self::String(s) => s.clone(),
+			Self::Int(i) => i.to_string(),
+			Self::Long(l) => l.to_string(),
+			Self::Float(f) => f.to_string(),
+			Self::Bool(b) => b.to_string(),
+			// am splits string arrays at commas that aren't preceded by a backslash.
+			Self::StringArray(items) => items.iter().map(|item| item.replace(',', "\\,")).collect::<Vec<_>>().join(","),
+		}
+	}
+}
+
+/// The extras of one intent, in the order they were added. Each key may only be set once.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Extras {
+	entries: Vec<(String, ExtraValue)>,
+}
+
+impl Extras {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn insert(&mut self, key: impl Into<String>, value: ExtraValue) -> Result<&mut Self, String> {
+		let key = key.into();
+		if key.is_empty() {
+			return Err("empty extra key".to_owned());
+		}
+		if self.get(&key).is_some() {
+			return Err(format!("extra {} is set more than once", key));
+		}
+		self.entries.push((key, value));
+		Ok(self)
+	}
+
+	/// Adds an extra given with `--okc-extra`, checking that the value matches its type.
+	pub fn insert_extra(&mut self, extra: &Extra) -> Result<&mut Self, String> {
+		let value = ExtraValue::parse(extra.flag, &extra.value).map_err(|e| format!("extra {}: {}", extra.key, e))?;
+		self.insert(extra.key.clone(), value)
+	}
+
+	pub fn get(&self, key: &str) -> Option<&ExtraValue> {
+		self.entries.iter().find(|(k, _)| k == key).map(|(_, value)| value)
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = (&str, &ExtraValue)> {
+		self.entries.iter().map(|(key, value)| (key.as_str(), value))
+	}
+
+	/// The `am` arguments for the extras, three per extra.
+	pub fn render(&self) -> Vec<String> {
+		self.entries.iter()
+			.flat_map(|(key, value)| vec![value.flag().to_owned(), key.clone(), value.render()])
+			.collect()
+	}
+}
@@ -0,0 +1,60 @@
+//! Just enough JSON output for the machine-readable reports, without pulling in a serialization framework.
+
+use std::fmt::Write;
+
+/// Quotes and escapes `s` as a JSON string.
+pub fn string(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+/// Formats the already encoded `values` as a JSON array.
+pub fn array(values: impl IntoIterator<Item = String>) -> String {
+	format!("[{}]", values.into_iter().collect::<Vec<_>>().join(","))
+}
+
+/// Builds a JSON object from already encoded values.
+#[derive(Default)]
+pub struct Object {
+	fields: Vec<String>,
+}
+
+impl Object {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn raw(mut self, name: &str, value: impl ToString) -> Self {
+		self.fields.push(format!("{}:{}", string(name), value.to_string()));
+		self
+	}
+
+	pub fn str(self, name: &str, value: &str) -> Self {
+		self.raw(name, string(value))
+	}
+
+	/// Adds `value` if it is present and `null` otherwise.
+	pub fn opt(self, name: &str, value: Option<impl ToString>) -> Self {
+		match value {
+			Some(value) => self.raw(name, value),
+			None => self.raw(name, "null"),
+		}
+	}
+
+	pub fn finish(self) -> String {
+		format!("{{{}}}", self.fields.join(","))
+	}
+}
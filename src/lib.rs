@@ -1,5 +1,7 @@
+extern crate base64;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 #[macro_use]
 extern crate slog;
 extern crate slog_async;
@@ -7,16 +9,36 @@ extern crate slog_envlogger;
 extern crate slog_term;
 extern crate tokio;
 
+pub mod args;
+pub mod bench;
+pub mod crc32;
+pub mod deflate;
+pub mod gpg;
+pub mod intent;
+pub mod json;
+pub mod logcat;
+pub mod mock;
+pub mod proto;
+pub mod record;
+pub mod text;
+
 pub mod utils {
 	use std::error::Error;
 	use std::fmt::{Display, Formatter};
 	use std::future::Future;
+	use std::io;
+	use std::path::Path;
+	use std::string::FromUtf8Error;
 	use std::sync::Mutex;
-	use slog::{Drain, Logger};
+	use std::time::Duration;
+	use slog::{Drain, Duplicate, Logger};
 	use slog_async::{Async, AsyncGuard};
 	use slog_term::{FullFormat, TermDecorator};
+	use tokio::sync::{RwLock, RwLockReadGuard};
+	use tokio::time;
+	use crate::logcat::{self, LogcatDrain};
 
-	pub type Result = std::result::Result<(), Box<dyn Error>>;
+	pub type Result<T = (), E = Box<dyn Error>> = std::result::Result<T, E>;
 
 	#[derive(Debug)]
 	pub struct StringError(pub String);
@@ -35,31 +57,243 @@ pub mod utils {
 		}
 	}
 
+	/// Errors returned by the library APIs, so that callers can tell failures apart.
+	#[derive(Debug)]
+	pub enum OkcError {
+		Io(io::Error),
+		Utf8(FromUtf8Error),
+		/// The peer violated the wire protocol.
+		Protocol(String),
+		/// The app finished the operation with a non-zero status code.
+		App(u8),
+		/// Something took longer than it is allowed to.
+		Timeout(String),
+		Other(String),
+	}
+
+	impl OkcError {
+		pub fn protocol(s: impl AsRef<str>) -> Self {
+			Self::Protocol(s.as_ref().to_owned())
+		}
+	}
+
+	impl Display for OkcError {
+		fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), std::fmt::Error> {
+			match self {
+				Self::Io(e) => write!(f, "I/O error: {}", e),
+				Self::Utf8(e) => write!(f, "invalid UTF-8 string: {}", e),
+				Self::Protocol(s) => write!(f, "protocol error: {}", s),
+				Self::App(code) => write!(f, "an error has occurred in the app (status code {})", code),
+				Self::Timeout(s) | Self::Other(s) => s.fmt(f),
+			}
+		}
+	}
+
+	impl Error for OkcError {
+		fn source(&self) -> Option<&(dyn Error + 'static)> {
+			match self {
+				Self::Io(e) => Some(e),
+				Self::Utf8(e) => Some(e),
+				_ => None,
+			}
+		}
+	}
+
+	impl From<io::Error> for OkcError {
+		fn from(e: io::Error) -> Self {
+			Self::Io(e)
+		}
+	}
+
+	impl From<FromUtf8Error> for OkcError {
+		fn from(e: FromUtf8Error) -> Self {
+			Self::Utf8(e)
+		}
+	}
+
+	impl From<StringError> for OkcError {
+		fn from(e: StringError) -> Self {
+			Self::Other(e.0)
+		}
+	}
+
+
+	/// Adds a `tag` field with this value to every log line, to tell concurrent instances apart.
+	pub const LOG_TAG_ENV: &str = "OKC_LOG_TAG";
+
+	pub const EXIT_GRACE_PERIOD: Duration = Duration::from_millis(500);
 
 	lazy_static! {
 		pub static ref LOG_GUARD: Mutex<Option<AsyncGuard>> = Mutex::new(None);
+		static ref FLUSH_LOCK: RwLock<()> = RwLock::new(());
+		static ref STDOUT_STATE: Mutex<StdoutState> = Mutex::new(StdoutState::default());
+	}
+
+	pub type FlushGuard = RwLockReadGuard<'static, ()>;
+
+	/// Marks an output flush as in progress until the returned guard is dropped, see [`exit_error`].
+	pub async fn begin_flush() -> FlushGuard {
+		FLUSH_LOCK.read().await
 	}
 
-	pub fn exit_process(code: i32) -> ! {
+	#[derive(Default)]
+	struct StdoutState {
+		/// The number of data streams being written to stdout.
+		writers: usize,
+		/// The messages printed while they were, see [`print_message`].
+		queued: Vec<String>,
+	}
+
+	/// Marks data as being written to stdout until the returned guard is dropped, see [`print_message`].
+	pub struct StdoutGuard(());
+
+	pub fn begin_stdout() -> StdoutGuard {
+		STDOUT_STATE.lock().unwrap().writers += 1;
+		StdoutGuard(())
+	}
+
+	impl Drop for StdoutGuard {
+		fn drop(&mut self) {
+			use std::io::Write;
+			let mut state = STDOUT_STATE.lock().unwrap();
+			state.writers -= 1;
+			if state.writers == 0 && !state.queued.is_empty() {
+				let mut stdout = io::stdout().lock();
+				for line in state.queued.drain(..) {
+					let _ = writeln!(stdout, "{}", line);
+				}
+				let _ = stdout.flush();
+			}
+		}
+	}
+
+	/// Prints a line to stdout, or once the data being written there is done, so that it doesn't end up
+	/// in the middle of the data.
+	pub fn print_message(line: String) {
+		let mut state = STDOUT_STATE.lock().unwrap();
+		if state.writers > 0 {
+			state.queued.push(line);
+		} else {
+			println!("{}", line);
+		}
+	}
+
+	/// Why the process is exiting, mapped to its exit status by [`exit_code`].
+	#[derive(Debug)]
+	pub enum ExitReason<'a> {
+		Success,
+		/// Running failed with this error.
+		Error(&'a (dyn Error + 'static)),
+		/// The signal with this number asked the process to terminate.
+		Signal(i32),
+		/// The command run by the process exited with this status, or was killed by a signal if there is none.
+		Child(Option<i32>),
+		/// Startup failed before logging was set up, the reason has already been printed.
+		Startup,
+	}
+
+	/// The exit status for a protocol error, `EX_PROTOCOL` of sysexits.h.
+	pub const EXIT_PROTOCOL: i32 = 76;
+	/// The exit status for a timeout, the one timeout(1) uses.
+	pub const EXIT_TIMEOUT: i32 = 124;
+
+	/// The exit status for `reason`. A status code reported by the app is passed through as it is, so the
+	/// caller sees what gpg would have exited with, even where it happens to be one of the others. A signal
+	/// gives 128 plus its number like in a shell, and any other failure 1.
+	pub fn exit_code(reason: &ExitReason<'_>) -> i32 {
+		match *reason {
+			ExitReason::Success => 0,
+			ExitReason::Child(Some(code)) => code,
+			ExitReason::Error(e) => match e.downcast_ref::<OkcError>() {
+				Some(OkcError::App(status)) if *status != 0 => i32::from(*status),
+				Some(OkcError::Protocol(_)) => EXIT_PROTOCOL,
+				Some(OkcError::Timeout(_)) => EXIT_TIMEOUT,
+				_ => 1,
+			},
+			ExitReason::Signal(signal) => 128 + signal,
+			ExitReason::Child(None) | ExitReason::Startup => 1,
+		}
+	}
+
+	/// Drops the [`LOG_GUARD`], which returns once every record logged so far has been written.
+	pub fn flush_logs() {
 		if let Some(guard) = LOG_GUARD.lock().unwrap().take() {
 			std::mem::drop(guard);
 		}
-		std::process::exit(code)
+	}
+
+	/// Flushes the logs and exits with the status for `reason`. All termination goes through here.
+	pub fn terminate(reason: ExitReason<'_>) -> ! {
+		// Nothing takes a lockfile or an advisory lock, the only state to release is the log guard. Anything
+		// added later that must not outlive the process, such as a lockfile, has to be released here too.
+		flush_logs();
+		std::process::exit(exit_code(&reason))
+	}
+
+	/// Terminates after waiting up to [`EXIT_GRACE_PERIOD`] for in-flight flushes to finish, so that an
+	/// error on one connection doesn't leave a truncated file behind on another.
+	pub async fn exit_error(reason: ExitReason<'_>) -> ! {
+		let _ = time::timeout(EXIT_GRACE_PERIOD, FLUSH_LOCK.write()).await;
+		terminate(reason)
+	}
+
+	const DEFAULT_LOG_SPEC: &str = "warn";
+
+	/// Checks a `RUST_LOG` value against the syntax accepted by slog_envlogger: comma separated
+	/// `level`, `module` or `module=level` directives, optionally followed by `/` and a filter.
+	pub fn check_log_spec(spec: &str) -> std::result::Result<(), String> {
+		let mut parts = spec.splitn(3, '/');
+		let directives = parts.next().unwrap_or_default();
+		if parts.nth(1).is_some() {
+			return Err("more than one '/'".to_owned());
+		}
+		for directive in directives.split(',').filter(|s| !s.is_empty()) {
+			let mut parts = directive.split('=');
+			match (parts.next(), parts.next().map(str::trim), parts.next()) {
+				(_, None, None) | (_, Some(""), None) => {}
+				(_, Some(level), None) => if level.parse::<slog::FilterLevel>().is_err() {
+					return Err(format!("unknown level {:?}", level));
+				},
+				_ => return Err(format!("more than one '=' in {:?}", directive)),
+			}
+		}
+		Ok(())
 	}
 
 	#[tokio::main]
 	pub async fn lib_main<T>(run: impl FnOnce(Logger) -> T) where T: Future<Output = Result> {
-		if std::env::var("RUST_LOG").map(|s| s.is_empty()).unwrap_or(true) {
-			std::env::set_var("RUST_LOG", "warn");
+		match std::env::var("RUST_LOG") {
+			Ok(spec) if !spec.is_empty() => if let Err(e) = check_log_spec(&spec) {
+				// slog_envlogger would skip the bad parts and print its complaints to stdout, where
+				// okc-gpg writes data, so fall back to the default instead.
+				eprintln!("warning: RUST_LOG={:?} could not be parsed ({}), using {} instead", spec, e, DEFAULT_LOG_SPEC);
+				std::env::set_var("RUST_LOG", DEFAULT_LOG_SPEC);
+			},
+			_ => std::env::set_var("RUST_LOG", DEFAULT_LOG_SPEC),
 		}
 		let drain = FullFormat::new(TermDecorator::new().stderr().build()).build().ignore_res();
+		let use_logcat = matches!(std::env::var(logcat::LOGCAT_ENV).as_deref(), Ok("1"));
+		let drain: Box<dyn Drain<Ok = (), Err = slog::Never> + Send> = if use_logcat {
+			let tag = std::env::args_os().next()
+				.and_then(|arg| Path::new(&arg).file_name().map(|s| s.to_string_lossy().into_owned()))
+				.unwrap_or_else(|| "okc-agents".to_owned());
+			Box::new(Duplicate::new(drain, LogcatDrain::new(&tag).ignore_res()).ignore_res())
+		} else {
+			Box::new(drain)
+		};
 		let drain = slog_envlogger::new(drain).ignore_res();
 		let (drain, guard) = Async::new(drain).build_with_guard();
 		*LOG_GUARD.lock().unwrap() = Some(guard);
-		let logger = Logger::root(drain.ignore_res(), o!());
+		let logger = match std::env::var(LOG_TAG_ENV) {
+			Ok(tag) if !tag.is_empty() => Logger::root(drain.ignore_res(), o!("tag" => tag)),
+			_ => Logger::root(drain.ignore_res(), o!()),
+		};
+		if use_logcat && !logcat::AVAILABLE {
+			warn!(logger, "{} is set but logcat is only available on Android", logcat::LOGCAT_ENV);
+		}
 		if let Err(e) = run(logger.clone()).await {
 			error!(logger, "{:?}", e);
-			exit_process(1);
+			exit_error(ExitReason::Error(&*e)).await;
 		}
 	}
 }
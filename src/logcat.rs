@@ -0,0 +1,70 @@
+//! A slog drain forwarding records to the Android log, so that they can be read with `logcat`.
+
+use std::ffi::CString;
+use std::fmt::{self, Write};
+use slog::{Drain, Key, Level, OwnedKVList, Record, KV};
+
+pub const LOGCAT_ENV: &str = "OKC_LOG_LOGCAT";
+
+#[cfg(target_os = "android")]
+#[link(name = "log")]
+extern "C" {
+	fn __android_log_write(prio: libc::c_int, tag: *const libc::c_char, text: *const libc::c_char) -> libc::c_int;
+}
+
+/// Whether records can actually reach logcat on this platform.
+pub const AVAILABLE: bool = cfg!(target_os = "android");
+
+fn priority(level: Level) -> i32 {
+	match level {
+		Level::Critical => 7,
+		Level::Error => 6,
+		Level::Warning => 5,
+		Level::Info => 4,
+		Level::Debug => 3,
+		Level::Trace => 2,
+	}
+}
+
+struct KvFormatter<'a>(&'a mut String);
+
+impl<'a> slog::Serializer for KvFormatter<'a> {
+	fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+		write!(self.0, ", {}: {}", key, val).map_err(|_| slog::Error::Other)
+	}
+}
+
+pub struct LogcatDrain {
+	tag: CString,
+}
+
+impl LogcatDrain {
+	pub fn new(tag: &str) -> Self {
+		Self { tag: CString::new(tag.replace('\0', "")).unwrap() }
+	}
+
+	#[cfg(target_os = "android")]
+	fn write(&self, level: Level, text: CString) {
+		unsafe {
+			__android_log_write(priority(level), self.tag.as_ptr(), text.as_ptr());
+		}
+	}
+
+	#[cfg(not(target_os = "android"))]
+	fn write(&self, level: Level, text: CString) {
+		let _ = (priority(level), &self.tag, text);
+	}
+}
+
+impl Drain for LogcatDrain {
+	type Ok = ();
+	type Err = slog::Error;
+
+	fn log(&self, record: &Record, values: &OwnedKVList) -> slog::Result {
+		let mut text = record.msg().to_string();
+		record.kv().serialize(record, &mut KvFormatter(&mut text))?;
+		values.serialize(record, &mut KvFormatter(&mut text))?;
+		self.write(record.level(), CString::new(text.replace('\0', "")).unwrap());
+		Ok(())
+	}
+}
@@ -0,0 +1,65 @@
+//! The app's side of the protocol, shared by okc-mock-app and the integration tests so that they can't drift
+//! apart.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::proto::{MAX_STR_LEN, OP_CONTROL, read_bytes, write_frames};
+use crate::utils::OkcError;
+
+/// Connects to okc-gpg on `port` and sends the connection type `op`, followed by the tag if it has one.
+pub async fn connect(port: u16, op: &[u8]) -> Result<TcpStream, OkcError> {
+	let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+	stream.write_all(op).await?;
+	Ok(stream)
+}
+
+/// Writes `s` with its length in front, as paths and control messages are sent.
+pub async fn write_str<T: AsyncWrite + Unpin>(tx: &mut T, s: &str) -> Result<(), OkcError> {
+	if s.len() > MAX_STR_LEN {
+		return Err(OkcError::Other(format!("a string of {} bytes exceeds the maximum of {}", s.len(), MAX_STR_LEN)));
+	}
+	tx.write_u16(s.len() as u16).await?;
+	tx.write_all(s.as_bytes()).await?;
+	Ok(())
+}
+
+/// Reads frames until the terminating empty one.
+pub async fn read_frames<T: AsyncRead + Unpin>(rx: &mut T) -> Result<Vec<u8>, OkcError> {
+	let mut data = Vec::new();
+	loop {
+		let frame = read_bytes(rx, u16::MAX as usize).await?;
+		if frame.is_empty() {
+			return Ok(data);
+		}
+		data.extend_from_slice(&frame);
+	}
+}
+
+/// Opens a data connection for `path` and reads everything okc-gpg sends.
+pub async fn read_input(port: u16, op: &[u8], path: &str) -> Result<Vec<u8>, OkcError> {
+	let mut stream = connect(port, op).await?;
+	write_str(&mut stream, path).await?;
+	read_frames(&mut stream).await
+}
+
+/// Opens a data connection for `path`, sends `data` and waits until okc-gpg has finished writing it, which
+/// it signals by closing the connection.
+pub async fn write_output(port: u16, op: &[u8], path: &str, data: &[u8]) -> Result<(), OkcError> {
+	let mut stream = connect(port, op).await?;
+	write_str(&mut stream, path).await?;
+	write_frames(&mut stream, data).await?;
+	stream.write_u16(0).await?;
+	stream.read_to_end(&mut Vec::new()).await?;
+	Ok(())
+}
+
+/// Opens the control connection and sends `messages` followed by the status code.
+pub async fn finish(port: u16, messages: &[&str], status: u8) -> Result<(), OkcError> {
+	let mut stream = connect(port, &[OP_CONTROL]).await?;
+	for msg in messages {
+		write_str(&mut stream, msg).await?;
+	}
+	write_str(&mut stream, "").await?;
+	stream.write_u8(status).await?;
+	Ok(())
+}
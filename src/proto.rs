@@ -0,0 +1,214 @@
+//! Framing shared by the connections between okc-gpg and the app.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::time;
+use crate::utils::{OkcError, Result};
+
+/// The version of this protocol, sent in the broadcast. Bump it on incompatible changes.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+pub const OP_CONTROL: u8 = 0;
+pub const OP_INPUT: u8 = 1;
+pub const OP_OUTPUT: u8 = 2;
+/// A data connection whose role is given by the byte following the connection type.
+pub const OP_TAGGED: u8 = 3;
+/// Set by the app in the connection type byte to request compressed transfer on that connection.
+pub const OP_FLAG_COMPRESSED: u8 = 0x80;
+/// Set by the app on an output connection to send a second string after the path, holding the original
+/// filename of the data or an empty string if it isn't known.
+pub const OP_FLAG_FILENAME: u8 = 0x40;
+/// Set by the app on an input connection to send the offset and length of the range it wants after the
+/// path, both as big-endian `u64`. A length of `u64::MAX` reads to the end.
+pub const OP_FLAG_RANGE: u8 = 0x20;
+/// Set by the app on an input connection to pull the data instead of having it pushed. After the path and
+/// range, the app sends big-endian `u32` credits, each allowing okc-gpg to read that many more bytes from the
+/// source. A credit of 0 ends the stream early, as if the source had ended.
+pub const OP_FLAG_PULL: u8 = 0x10;
+/// Set by the app on an output connection to send the metadata of the file after the path and filename: its
+/// mode as a big-endian `u32`, of which only the permission bits are used, and its modification time in
+/// milliseconds since the Unix epoch as a big-endian `u64`. Either may be all ones to leave it as it is.
+pub const OP_FLAG_METADATA: u8 = 0x08;
+pub const OP_FLAGS: u8 = OP_FLAG_COMPRESSED | OP_FLAG_FILENAME | OP_FLAG_RANGE | OP_FLAG_PULL | OP_FLAG_METADATA;
+
+/// What a data connection is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+	/// The primary input, which may be stdin.
+	Input,
+	/// The primary output, which may be stdout.
+	Output,
+	/// An auxiliary file the app needs to read, such as a keyring.
+	Aux,
+	/// A detached signature, written separately from the data stream.
+	Signature,
+}
+
+impl Role {
+	pub fn from_tag(tag: u8) -> Option<Self> {
+		match tag {
+			0 => Some(Self::Input),
+			1 => Some(Self::Output),
+			2 => Some(Self::Aux),
+			3 => Some(Self::Signature),
+			_ => None,
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Input => "input",
+			Self::Output => "output",
+			Self::Aux => "aux",
+			Self::Signature => "signature",
+		}
+	}
+
+	/// Whether data flows from okc-gpg to the app on connections with this role.
+	pub fn is_read(self) -> bool {
+		matches!(self, Self::Input | Self::Aux)
+	}
+
+	/// Whether the path may be `-` to refer to stdin or stdout.
+	pub fn allows_stdio(self) -> bool {
+		matches!(self, Self::Input | Self::Output | Self::Signature)
+	}
+}
+
+/// Upper bound for the length of strings read by [`read_str`].
+pub const MAX_STR_LEN: usize = u16::MAX as usize;
+
+// The backlog of the listener, as used by std and tokio when binding directly.
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// Binds the listener the app connects back to, on an ephemeral port if `port` is 0. It must only ever be
+/// reachable from the device itself.
+///
+/// A fixed port is bound with `SO_REUSEADDR`, so that connections of a previous run lingering in
+/// `TIME_WAIT` don't keep it from being bound again. Other listeners on it still make binding fail.
+pub async fn bind_loopback(port: u16) -> io::Result<TcpListener> {
+	let addr = SocketAddr::from(([127, 0, 0, 1], port));
+	if port == 0 {
+		return TcpListener::bind(addr).await;
+	}
+	let socket = TcpSocket::new_v4()?;
+	socket.set_reuseaddr(true)?;
+	socket.bind(addr)?;
+	socket.listen(LISTEN_BACKLOG)
+}
+
+/// Fills `buf` like `read_exact`, but retries reads interrupted by a signal. Unlike std, tokio passes
+/// [`io::ErrorKind::Interrupted`] on, and some Android kernels do interrupt blocking reads.
+pub async fn read_full<T: AsyncRead + Unpin>(rx: &mut T, buf: &mut [u8]) -> io::Result<()> {
+	let mut pos = 0;
+	while pos < buf.len() {
+		match rx.read(&mut buf[pos..]).await {
+			Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+			Ok(len) => pos += len,
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(())
+}
+
+/// Reads a single header byte, such as a connection type or status code, see [`read_full`].
+pub async fn read_byte<T: AsyncRead + Unpin>(rx: &mut T) -> io::Result<u8> {
+	let mut buf = [0u8; 1];
+	read_full(rx, &mut buf).await?;
+	Ok(buf[0])
+}
+
+/// Reads a big-endian `u16` length prefix, see [`read_full`].
+pub async fn read_len<T: AsyncRead + Unpin>(rx: &mut T) -> io::Result<u16> {
+	let mut buf = [0u8; 2];
+	read_full(rx, &mut buf).await?;
+	Ok(u16::from_be_bytes(buf))
+}
+
+/// Reads a big-endian `u32`, see [`read_full`].
+pub async fn read_u32<T: AsyncRead + Unpin>(rx: &mut T) -> io::Result<u32> {
+	let mut buf = [0u8; 4];
+	read_full(rx, &mut buf).await?;
+	Ok(u32::from_be_bytes(buf))
+}
+
+/// Reads a big-endian `u64`, see [`read_full`].
+pub async fn read_u64<T: AsyncRead + Unpin>(rx: &mut T) -> io::Result<u64> {
+	let mut buf = [0u8; 8];
+	read_full(rx, &mut buf).await?;
+	Ok(u64::from_be_bytes(buf))
+}
+
+/// Reads a byte string prefixed by its length as a big-endian `u16`, rejecting lengths above `max_len`
+/// before anything is allocated.
+pub async fn read_bytes<T: AsyncRead + Unpin>(rx: &mut T, max_len: usize) -> Result<Vec<u8>, OkcError> {
+	let len = read_len(rx).await? as usize;
+	if len > max_len {
+		return Err(OkcError::protocol(format!("frame of {} bytes exceeds the limit of {} bytes", len, max_len)));
+	}
+	let mut buf = vec!(0u8; len);
+	read_full(rx, &mut buf).await?;
+	Ok(buf)
+}
+
+/// Reads a UTF-8 string prefixed by its length as a big-endian `u16`.
+pub async fn read_str<T: AsyncRead + Unpin>(rx: &mut T) -> Result<String, OkcError> {
+	Ok(String::from_utf8(read_bytes(rx, MAX_STR_LEN).await?)?)
+}
+
+/// How much of an invalid control message [`read_message`] shows.
+const PREVIEW_LEN: usize = 64;
+/// Control messages are short lines of text, a longer one means the length was read from the wrong place.
+pub const MAX_MESSAGE_LEN: usize = 16 << 10;
+/// The app writes each message at once, so its text must follow its length promptly.
+pub const MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads a control message like [`read_str`], but reports invalid UTF-8 with the message's length and a
+/// lossy preview of it, since such messages would otherwise be impossible to debug. Paths keep using
+/// [`read_str`], whose errors point at the path.
+///
+/// Lengths that can't belong to a message fail as desynchronized rather than taking the bytes that follow,
+/// such as the status code, as text: too large ones, ones the app doesn't send as many bytes for in time,
+/// and ones covering NUL bytes. The connection closing within a message is an I/O error like anywhere else,
+/// since that is what the app being killed looks like.
+pub async fn read_message<T: AsyncRead + Unpin>(rx: &mut T) -> Result<String, OkcError> {
+	let desync = |reason: String| OkcError::protocol(format!("control stream desynchronized: {}", reason));
+	let len = read_len(rx).await? as usize;
+	if len > MAX_MESSAGE_LEN {
+		return Err(desync(format!("message length {} exceeds {} bytes", len, MAX_MESSAGE_LEN)));
+	}
+	let mut buf = vec![0u8; len];
+	match time::timeout(MESSAGE_TIMEOUT, read_full(rx, &mut buf)).await {
+		Ok(Ok(())) => {}
+		Ok(Err(e)) => return Err(e.into()),
+		Err(_) => {
+			return Err(desync(format!(
+				"a message of {} bytes did not arrive within {} seconds", len, MESSAGE_TIMEOUT.as_secs()
+			)));
+		}
+	}
+	if buf.contains(&0) {
+		return Err(desync(format!("message of {} bytes contains NUL bytes", len)));
+	}
+	String::from_utf8(buf).map_err(|e| {
+		let bytes = e.as_bytes();
+		let preview = String::from_utf8_lossy(&bytes[..bytes.len().min(PREVIEW_LEN)]);
+		OkcError::protocol(format!(
+			"control message of {} bytes is not valid UTF-8 ({}), starting with {:?}", bytes.len(), e.utf8_error(), preview,
+		))
+	})
+}
+
+/// Writes `data` as a sequence of length-prefixed frames. Nothing is written for empty data since an
+/// empty frame terminates the stream.
+pub async fn write_frames<T: AsyncWrite + Unpin>(tx: &mut T, data: &[u8]) -> Result<(), OkcError> {
+	for chunk in data.chunks(u16::MAX as usize) {
+		tx.write_u16(chunk.len() as u16).await?;
+		tx.write_all(chunk).await?;
+	}
+	Ok(())
+}
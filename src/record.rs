@@ -0,0 +1,313 @@
+//! Recording of the protocol events of a session, and replaying the app's side of a recording.
+//!
+//! A recording is a text file with one event per line, strings and data are base64 encoded:
+//!
+//! - `broadcast <port> <args>`: the broadcast, with the arguments comma-separated or `-` if there are none.
+//! - `connect <id> <op> <tag>`: a connection and its type byte, the tag is `-` for untagged connections.
+//! - `str <id> <string>`: a string received from the app, such as a path or a control message.
+//! - `range <id> <offset> <len>`: the input range requested by the app.
+//! - `credit <id> <len>`: a credit sent by the app on a pulled input connection.
+//! - `metadata <id> <mode> <mtime>`: the file metadata sent by the app on an output connection.
+//! - `frame <id> <len> [data]`: a data frame received from the app, an empty one ends the stream.
+//! - `sent <id> <len> [data]`: frames sent to the app, 0 for the terminator.
+//! - `status <id> <code>`: the status code on the control connection.
+//! - `close <id>`: okc-gpg is done with the connection.
+//!
+//! The data is only there if it was explicitly allowed, as it's usually plaintext.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::Mutex;
+use futures_util::future::BoxFuture;
+use slog::Logger;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::gpg::Broadcaster;
+use crate::utils::OkcError;
+
+/// Writes the events of a session to a file.
+pub struct Recorder {
+	file: Mutex<File>,
+	with_data: bool,
+	logger: Logger,
+}
+
+impl Recorder {
+	pub fn create(path: &str, with_data: bool, logger: Logger) -> Result<Self, OkcError> {
+		let file = File::create(path)
+			.map_err(|e| OkcError::Other(format!("failed to create recording {:?}: {}", path, e)))?;
+		Ok(Self { file: Mutex::new(file), with_data, logger })
+	}
+
+	fn write(&self, line: String) {
+		if let Err(e) = writeln!(self.file.lock().unwrap(), "{}", line) {
+			warn!(self.logger, "failed to write to the recording: {}", e);
+		}
+	}
+
+	fn data(&self, data: &[u8]) -> String {
+		if self.with_data && !data.is_empty() { format!(" {}", base64::encode(data)) } else { String::new() }
+	}
+
+	pub fn broadcast(&self, port: u16, args: &[String]) {
+		let args = if args.is_empty() {
+			"-".to_owned()
+		} else {
+			args.iter().map(base64::encode).collect::<Vec<_>>().join(",")
+		};
+		self.write(format!("broadcast {} {}", port, args));
+	}
+}
+
+/// The recorder as seen by one connection, doing nothing if the session isn't recorded.
+#[derive(Clone, Copy)]
+pub struct Tap<'a> {
+	pub recorder: Option<&'a Recorder>,
+	pub id: u64,
+}
+
+impl<'a> Tap<'a> {
+	pub fn none() -> Self {
+		Self { recorder: None, id: 0 }
+	}
+
+	pub fn connect(self, op: u8, tag: Option<u8>) {
+		if let Some(recorder) = self.recorder {
+			let tag = tag.map_or_else(|| "-".to_owned(), |tag| tag.to_string());
+			recorder.write(format!("connect {} {} {}", self.id, op, tag));
+		}
+	}
+
+	pub fn str(self, s: &str) {
+		if let Some(recorder) = self.recorder {
+			recorder.write(format!("str {} {}", self.id, base64::encode(s)));
+		}
+	}
+
+	pub fn range(self, offset: u64, len: u64) {
+		if let Some(recorder) = self.recorder {
+			recorder.write(format!("range {} {} {}", self.id, offset, len));
+		}
+	}
+
+	pub fn credit(self, len: u32) {
+		if let Some(recorder) = self.recorder {
+			recorder.write(format!("credit {} {}", self.id, len));
+		}
+	}
+
+	pub fn metadata(self, mode: u32, mtime_ms: u64) {
+		if let Some(recorder) = self.recorder {
+			recorder.write(format!("metadata {} {} {}", self.id, mode, mtime_ms));
+		}
+	}
+
+	/// Whether the data passed to `frame` and `sent` is recorded, so callers can skip collecting it.
+	pub fn records_data(self) -> bool {
+		matches!(self.recorder, Some(recorder) if recorder.with_data)
+	}
+
+	pub fn frame(self, len: usize, data: &[u8]) {
+		if let Some(recorder) = self.recorder {
+			recorder.write(format!("frame {} {}{}", self.id, len, recorder.data(data)));
+		}
+	}
+
+	pub fn sent(self, len: usize, data: &[u8]) {
+		if let Some(recorder) = self.recorder {
+			recorder.write(format!("sent {} {}{}", self.id, len, recorder.data(data)));
+		}
+	}
+
+	pub fn status(self, code: u8) {
+		if let Some(recorder) = self.recorder {
+			recorder.write(format!("status {} {}", self.id, code));
+		}
+	}
+
+	pub fn close(self) {
+		if let Some(recorder) = self.recorder {
+			recorder.write(format!("close {}", self.id));
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+	Broadcast { args: Vec<String> },
+	Connect { id: u64, op: u8, tag: Option<u8> },
+	Str { id: u64, value: String },
+	Range { id: u64, offset: u64, len: u64 },
+	Credit { id: u64, len: u32 },
+	Metadata { id: u64, mode: u32, mtime_ms: u64 },
+	Frame { id: u64, len: usize, data: Option<Vec<u8>> },
+	Sent { id: u64, len: usize, data: Option<Vec<u8>> },
+	Status { id: u64, code: u8 },
+	Close { id: u64 },
+}
+
+fn parse_event(line: &str) -> Option<Event> {
+	let mut fields = line.split(' ');
+	let kind = fields.next()?;
+	if kind == "broadcast" {
+		fields.next()?;
+		let args = match fields.next()? {
+			"-" => Vec::new(),
+			args => args.split(',').map(|arg| base64::decode(arg).ok().and_then(|arg| String::from_utf8(arg).ok()))
+				.collect::<Option<_>>()?,
+		};
+		return Some(Event::Broadcast { args });
+	}
+	let id = fields.next()?.parse().ok()?;
+	Some(match kind {
+		"connect" => {
+			let op = fields.next()?.parse().ok()?;
+			let tag = match fields.next()? {
+				"-" => None,
+				tag => Some(tag.parse().ok()?),
+			};
+			Event::Connect { id, op, tag }
+		}
+		"str" => Event::Str { id, value: String::from_utf8(base64::decode(fields.next()?).ok()?).ok()? },
+		"range" => Event::Range { id, offset: fields.next()?.parse().ok()?, len: fields.next()?.parse().ok()? },
+		"credit" => Event::Credit { id, len: fields.next()?.parse().ok()? },
+		"metadata" => Event::Metadata { id, mode: fields.next()?.parse().ok()?, mtime_ms: fields.next()?.parse().ok()? },
+		"frame" | "sent" => {
+			let len = fields.next()?.parse().ok()?;
+			let data = match fields.next() {
+				Some(data) => Some(base64::decode(data).ok()?),
+				None => None,
+			};
+			if kind == "frame" { Event::Frame { id, len, data } } else { Event::Sent { id, len, data } }
+		}
+		"status" => Event::Status { id, code: fields.next()?.parse().ok()? },
+		"close" => Event::Close { id },
+		_ => return None,
+	})
+}
+
+/// Reads a recording written by [`Recorder`].
+pub fn load(path: &str) -> Result<Vec<Event>, OkcError> {
+	let file = File::open(path).map_err(|e| OkcError::Other(format!("failed to open recording {:?}: {}", path, e)))?;
+	BufReader::new(file).lines().enumerate().map(|(i, line)| {
+		parse_event(&line?).ok_or_else(|| OkcError::Other(format!("invalid event in {:?} on line {}", path, i + 1)))
+	}).collect()
+}
+
+/// Plays the app's side of a recording: connects as often as the app did, sending the same strings, frames
+/// and status codes. The connections are replayed one after another in the order they were opened. Frames
+/// recorded without their data are replaced by zeros, which won't work for compressed connections.
+///
+/// If the data okc-gpg sent was recorded, what it sends now must be the same, so that a recording doubles as
+/// a regression test of the protocol. Only the bytes are compared, the frames depend on the chunk size.
+pub struct ReplayBroadcaster {
+	pub events: Vec<Event>,
+	pub logger: Logger,
+}
+
+impl ReplayBroadcaster {
+	/// The arguments of the recorded broadcast.
+	pub fn args(&self) -> Vec<String> {
+		self.events.iter().find_map(|event| match event {
+			Event::Broadcast { args } => Some(args.clone()),
+			_ => None,
+		}).unwrap_or_default()
+	}
+}
+
+async fn replay_connection(port: u16, id: u64, events: &[Event]) -> Result<(), OkcError> {
+	let mut stream = None;
+	// What okc-gpg sent on the connection, if the recording has the data of all of its frames.
+	let mut expected = Some(Vec::new());
+	for event in events {
+		match *event {
+			Event::Connect { id: event_id, op, tag } if event_id == id => {
+				let mut s = TcpStream::connect(("127.0.0.1", port)).await?;
+				s.write_u8(op).await?;
+				if let Some(tag) = tag {
+					s.write_u8(tag).await?;
+				}
+				stream = Some(s);
+			}
+			_ => {}
+		}
+		let s = match stream {
+			Some(ref mut s) => s,
+			None => continue,
+		};
+		match event {
+			Event::Str { id: event_id, value } if *event_id == id => {
+				s.write_u16(value.len() as u16).await?;
+				s.write_all(value.as_bytes()).await?;
+			}
+			Event::Range { id: event_id, offset, len } if *event_id == id => {
+				s.write_u64(*offset).await?;
+				s.write_u64(*len).await?;
+			}
+			Event::Credit { id: event_id, len } if *event_id == id => s.write_u32(*len).await?,
+			Event::Metadata { id: event_id, mode, mtime_ms } if *event_id == id => {
+				s.write_u32(*mode).await?;
+				s.write_u64(*mtime_ms).await?;
+			}
+			Event::Frame { id: event_id, len, data } if *event_id == id => {
+				s.write_u16(*len as u16).await?;
+				match data {
+					Some(data) => s.write_all(data).await?,
+					None => s.write_all(&vec![0; *len]).await?,
+				}
+			}
+			Event::Sent { id: event_id, len: 0, .. } if *event_id == id => {
+				let mut received = Vec::new();
+				loop {
+					let len = s.read_u16().await? as usize;
+					if len == 0 {
+						break;
+					}
+					let start = received.len();
+					received.resize(start + len, 0);
+					s.read_exact(&mut received[start..]).await?;
+				}
+				if let Some(expected) = expected.take().filter(|expected| *expected != received) {
+					return Err(OkcError::protocol(format!(
+						"okc-gpg sent {} bytes on connection {} that differ from the {} recorded ones",
+						received.len(), id, expected.len(),
+					)));
+				}
+				expected = Some(Vec::new());
+			}
+			Event::Sent { id: event_id, data, .. } if *event_id == id => match (expected.as_mut(), data) {
+				(Some(expected), Some(data)) => expected.extend_from_slice(data),
+				_ => expected = None,
+			},
+			Event::Status { id: event_id, code } if *event_id == id => s.write_u8(*code).await?,
+			Event::Close { id: event_id } if *event_id == id => {
+				// Wait for okc-gpg to finish with the connection, as the app would.
+				let mut rest = Vec::new();
+				s.read_to_end(&mut rest).await?;
+				return Ok(());
+			}
+			_ => {}
+		}
+	}
+	Ok(())
+}
+
+async fn replay(port: u16, events: Vec<Event>, logger: Logger) {
+	let ids = events.iter().filter_map(|event| match event {
+		Event::Connect { id, .. } => Some(*id),
+		_ => None,
+	}).collect::<Vec<_>>();
+	for id in ids {
+		debug!(logger, "replaying connection {}", id);
+		if let Err(e) = replay_connection(port, id, &events).await {
+			warn!(logger, "failed to replay connection {}: {}", id, e);
+		}
+	}
+}
+
+impl Broadcaster for ReplayBroadcaster {
+	fn send<'a>(&'a self, port: u16, _args: &'a [String]) -> BoxFuture<'a, Result<(), OkcError>> {
+		tokio::spawn(replay(port, self.events.clone(), self.logger.clone()));
+		Box::pin(async { Ok(()) })
+	}
+}
@@ -0,0 +1,110 @@
+//! Line ending normalization for the optional text mode, analogous to GnuPG's `--textmode`.
+
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+	Lf,
+	CrLf,
+}
+
+impl FromStr for LineEnding {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, ()> {
+		match s {
+			"lf" | "LF" => Ok(Self::Lf),
+			"crlf" | "CRLF" => Ok(Self::CrLf),
+			_ => Err(()),
+		}
+	}
+}
+
+impl LineEnding {
+	fn as_bytes(self) -> &'static [u8] {
+		match self {
+			Self::Lf => b"\n",
+			Self::CrLf => b"\r\n",
+		}
+	}
+}
+
+/// Rewrites LF and CRLF line endings in a stream that arrives in arbitrary chunks. A lone CR is kept as is.
+pub struct Normalizer {
+	target: LineEnding,
+	// A CR at the end of the previous chunk, which may turn out to start a CRLF.
+	pending_cr: bool,
+}
+
+impl Normalizer {
+	pub fn new(target: LineEnding) -> Self {
+		Self { target, pending_cr: false }
+	}
+
+	pub fn convert(&mut self, data: &[u8], out: &mut Vec<u8>) {
+		for &b in data {
+			if self.pending_cr {
+				self.pending_cr = false;
+				if b == b'\n' {
+					out.extend_from_slice(self.target.as_bytes());
+					continue;
+				}
+				out.push(b'\r');
+			}
+			match b {
+				b'\r' => self.pending_cr = true,
+				b'\n' => out.extend_from_slice(self.target.as_bytes()),
+				_ => out.push(b),
+			}
+		}
+	}
+
+	pub fn finish(&mut self, out: &mut Vec<u8>) {
+		if self.pending_cr {
+			self.pending_cr = false;
+			out.push(b'\r');
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Normalizes `chunks` as if they arrived one after the other.
+	fn normalize(target: LineEnding, chunks: &[&[u8]]) -> Vec<u8> {
+		let mut normalizer = Normalizer::new(target);
+		let mut out = Vec::new();
+		for chunk in chunks {
+			normalizer.convert(chunk, &mut out);
+		}
+		normalizer.finish(&mut out);
+		out
+	}
+
+	#[test]
+	fn lf_to_crlf() {
+		assert_eq!(normalize(LineEnding::CrLf, &[b"a\nb\r\nc\n"]), b"a\r\nb\r\nc\r\n");
+		assert_eq!(normalize(LineEnding::Lf, &[b"a\nb\r\nc\r\n"]), b"a\nb\nc\n");
+	}
+
+	#[test]
+	fn crlf_split_across_chunks() {
+		assert_eq!(normalize(LineEnding::Lf, &[b"a\r", b"\nb"]), b"a\nb");
+		assert_eq!(normalize(LineEnding::CrLf, &[b"a\r", b"", b"\nb"]), b"a\r\nb");
+		// Only a CR directly followed by an LF is a line ending.
+		assert_eq!(normalize(LineEnding::Lf, &[b"a\r", b"b\n"]), b"a\rb\n");
+	}
+
+	#[test]
+	fn trailing_cr_is_kept() {
+		assert_eq!(normalize(LineEnding::Lf, &[b"a\r"]), b"a\r");
+		assert_eq!(normalize(LineEnding::CrLf, &[b"a", b"\r"]), b"a\r");
+	}
+
+	#[test]
+	fn empty_input() {
+		assert_eq!(normalize(LineEnding::CrLf, &[]), b"");
+		assert_eq!(normalize(LineEnding::Lf, &[b"", b""]), b"");
+	}
+}
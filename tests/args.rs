@@ -0,0 +1,73 @@
+use okc_agents::args::{self, Change, Extra, Profile};
+
+fn strings(args: &[&str]) -> Vec<String> {
+	args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn extras_are_taken_before_gpg_args() {
+	let mut args = strings(&["--okc-extra", "DEBUG=true", "--okc-extra=com.example.LEVEL:s=5", "--sign", "--okc-extra"]);
+	let extras = args::take_extras(&mut args).unwrap();
+	assert_eq!(extras, vec![
+		Extra { key: "org.ddosolitary.okcagent.extra.DEBUG".to_owned(), flag: "--ez", value: "true".to_owned() },
+		Extra { key: "com.example.LEVEL".to_owned(), flag: "--es", value: "5".to_owned() },
+	]);
+	assert_eq!(args, strings(&["--sign", "--okc-extra"]));
+}
+
+#[test]
+fn extra_types() {
+	let flag = |s: &str| s.parse::<Extra>().map(|extra| extra.flag);
+	assert_eq!(flag("a=5"), Ok("--ei"));
+	assert_eq!(flag("a=5000000000"), Ok("--es"));
+	assert_eq!(flag("a:l=5000000000"), Ok("--el"));
+	assert_eq!(flag("a:f=1.5"), Ok("--ef"));
+	assert_eq!(flag("a=text"), Ok("--es"));
+	assert!(flag("a:x=1").is_err());
+	assert!(flag("=1").is_err());
+	assert!(flag("a").is_err());
+	assert!(args::take_extras(&mut strings(&["--okc-extra"])).is_err());
+}
+
+#[test]
+fn batch_spec() {
+	let spec = "# encrypt both\n--encrypt -r alice 'my file.txt'\n\n  --sign \"say \\\"hi\\\"\" a\\ b\n";
+	assert_eq!(args::parse_batch(spec).unwrap(), vec![
+		strings(&["--encrypt", "-r", "alice", "my file.txt"]),
+		strings(&["--sign", "say \"hi\"", "a b"]),
+	]);
+	assert_eq!(args::parse_batch("--sign ''").unwrap(), vec![strings(&["--sign", ""])]);
+	assert!(args::parse_batch("--sign\n--encrypt 'open").unwrap_err().starts_with("line 2"));
+}
+
+#[test]
+fn input_arg_is_replaced_with_stdin() {
+	let args = strings(&["--sign", "-u", "me", "message.txt"]);
+	assert_eq!(args::take_input_arg(&args, -1).unwrap(), (strings(&["--sign", "-u", "me", "-"]), "message.txt".to_owned()));
+	assert_eq!(args::take_input_arg(&args, 3).unwrap().1, "me");
+	assert!(args::take_input_arg(&args, 0).is_err());
+	assert!(args::take_input_arg(&args, 5).is_err());
+	assert!(args::take_input_arg(&args, -5).is_err());
+	assert!(args::take_input_arg(&args, 1).is_err());
+}
+
+#[test]
+fn gpg_args_are_translated() {
+	let check = |profile, args: &[&str], expected: &[&str], changes: Vec<Change>| {
+		assert_eq!(args::translate(&strings(args), profile), (strings(expected), changes), "{:?}", args);
+	};
+	let dropped = |args: &[&str]| Change::Dropped(strings(args));
+	let renamed = |old: &str, new: &str| Change::Renamed(old.to_owned(), new.to_owned());
+	check(Profile::Gpg1, &["--use-agent", "--sign"], &["--sign"], vec![dropped(&["--use-agent"])]);
+	check(Profile::Gpg1, &["--clearsign", "msg"], &["--clear-sign", "msg"], vec![renamed("--clearsign", "--clear-sign")]);
+	check(Profile::Gpg1, &["--clearsign=x"], &["--clear-sign=x"], vec![renamed("--clearsign=x", "--clear-sign=x")]);
+	// Options of the other profile are left alone.
+	check(Profile::Gpg1, &["--no-autostart"], &["--no-autostart"], vec![]);
+	check(Profile::Gpg2, &["--pinentry-mode", "loopback", "--sign"], &["--sign"], vec![dropped(&["--pinentry-mode", "loopback"])]);
+	check(Profile::Gpg2, &["--pinentry-mode=loopback", "--sign"], &["--sign"], vec![dropped(&["--pinentry-mode=loopback"])]);
+	check(Profile::Gpg2, &["--agent-program"], &[], vec![dropped(&["--agent-program"])]);
+	check(Profile::Gpg2, &["--no-autostart", "--clearsign"], &["--clearsign"], vec![dropped(&["--no-autostart"])]);
+	// Everything after `--` is a file name.
+	check(Profile::Gpg2, &["--sign", "--", "--no-autostart"], &["--sign", "--", "--no-autostart"], vec![]);
+	check(Profile::Gpg2, &[], &[], vec![]);
+}
@@ -0,0 +1,81 @@
+// Helpers playing the app side of the protocol for the integration tests, panicking on errors.
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use futures_util::future::BoxFuture;
+use slog::Logger;
+use tokio::net::TcpStream;
+use okc_agents::gpg::Broadcaster;
+use okc_agents::mock;
+use okc_agents::utils::OkcError;
+
+pub type Script = Box<dyn Fn(u16) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Records each broadcast and spawns `script` with the advertised port, standing in for the app.
+pub struct MockApp {
+	pub calls: Mutex<Vec<(u16, Vec<String>)>>,
+	script: Script,
+}
+
+impl MockApp {
+	pub fn new(script: Script) -> Self {
+		Self { calls: Mutex::new(Vec::new()), script }
+	}
+}
+
+impl Broadcaster for MockApp {
+	fn send<'a>(&'a self, port: u16, args: &'a [String]) -> BoxFuture<'a, Result<(), OkcError>> {
+		self.calls.lock().unwrap().push((port, args.to_vec()));
+		tokio::spawn((self.script)(port));
+		Box::pin(async { Ok(()) })
+	}
+}
+
+pub fn logger() -> Logger {
+	Logger::root(slog::Discard, slog::o!())
+}
+
+pub fn temp_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("okc-agents-test-{}-{}", name, std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	std::fs::create_dir_all(&dir).unwrap();
+	dir
+}
+
+/// Waits for okc-gpg running with OKC_LISTEN_ONLY to write its port to `path`.
+pub async fn wait_for_port(path: &Path) -> u16 {
+	loop {
+		match std::fs::read_to_string(path).ok().and_then(|s| s.trim().parse::<u16>().ok()) {
+			Some(port) => return port,
+			None => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+		}
+	}
+}
+
+pub async fn connect(port: u16, op: &[u8]) -> TcpStream {
+	mock::connect(port, op).await.unwrap()
+}
+
+pub async fn send_str(stream: &mut TcpStream, s: &str) {
+	mock::write_str(stream, s).await.unwrap();
+}
+
+/// Opens a data connection for `path` and reads everything okc-gpg sends.
+pub async fn read_input(port: u16, op: &[u8], path: &str) -> Vec<u8> {
+	mock::read_input(port, op, path).await.unwrap()
+}
+
+/// Reads frames until the terminating empty one.
+pub async fn read_frames(stream: &mut TcpStream) -> Vec<u8> {
+	mock::read_frames(stream).await.unwrap()
+}
+
+/// Opens a data connection for `path`, sends `data` and waits until okc-gpg has finished writing it.
+pub async fn write_output(port: u16, op: &[u8], path: &str, data: &[u8]) {
+	mock::write_output(port, op, path, data).await.unwrap();
+}
+
+pub async fn finish(port: u16, warnings: &[&str], status: u8) {
+	mock::finish(port, warnings, status).await.unwrap();
+}
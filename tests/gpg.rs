@@ -0,0 +1,1354 @@
+mod common;
+
+use std::os::unix::fs::FileTypeExt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use futures_util::FutureExt;
+use futures_util::future::BoxFuture;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use okc_agents::deflate::Deflater;
+use okc_agents::gpg::{self, Backoff, Broadcaster, Limits, Options, Transfer, WarningSink};
+use okc_agents::proto::{OP_FLAG_METADATA, OP_FLAG_PULL, OP_FLAG_RANGE, OP_INPUT, OP_OUTPUT, PROTOCOL_VERSION, write_frames};
+use okc_agents::utils::OkcError;
+use common::*;
+
+#[tokio::test]
+async fn input_output_control() {
+	let dir = temp_dir("input_output_control");
+	let input = dir.join("input").to_str().unwrap().to_owned();
+	let output = dir.join("output").to_str().unwrap().to_owned();
+	std::fs::write(&input, b"hello world").unwrap();
+	let (input_path, output_path) = (input.clone(), output.clone());
+	let app = MockApp::new(Box::new(move |port| {
+		let (input, output) = (input_path.clone(), output_path.clone());
+		async move {
+			let data = read_input(port, &[1], &input).await;
+			write_output(port, &[2], &output, &data.to_ascii_uppercase()).await;
+			finish(port, &["[W] careful"], 0).await;
+		}.boxed()
+	}));
+	let args = vec!["--sign".to_owned()];
+	gpg::run(&app, &Options::default(), &args, logger()).await.unwrap();
+	assert_eq!(std::fs::read(&output).unwrap(), b"HELLO WORLD");
+	let calls = app.calls.lock().unwrap();
+	assert_eq!(calls.len(), 1);
+	assert_eq!(calls[0].1, args);
+}
+
+#[derive(Default)]
+struct Collector(Mutex<Vec<String>>);
+
+impl WarningSink for Collector {
+	fn warning(&self, msg: &str) {
+		self.0.lock().unwrap().push(msg.to_owned());
+	}
+}
+
+#[tokio::test]
+async fn warnings_go_to_sink() {
+	let app = MockApp::new(Box::new(|port| finish(port, &["[W] careful", "[C] 0", "plain"], 0).boxed()));
+	let sink = Arc::new(Collector::default());
+	let options = Options { warning_sink: Some(sink.clone()), ..Options::default() };
+	gpg::run(&app, &options, &[], logger()).await.unwrap();
+	assert_eq!(*sink.0.lock().unwrap(), vec!["[W] careful".to_owned(), "plain".to_owned()]);
+}
+
+#[tokio::test]
+async fn protocol_version_is_checked() {
+	let current = format!("[V] {}", PROTOCOL_VERSION);
+	let other = format!("[V] {}", PROTOCOL_VERSION + 1);
+	let cases = [(current.clone(), false, true), (current, true, true), (other.clone(), false, true), (other, true, false)];
+	for (version, strict_version, ok) in cases.iter().cloned() {
+		let message = version.clone();
+		let app = MockApp::new(Box::new(move |port| {
+			let message = message.clone();
+			async move { finish(port, &[&message], 0).await }.boxed()
+		}));
+		let sink = Arc::new(Collector::default());
+		let options = Options { strict_version, warning_sink: Some(sink.clone()), ..Options::default() };
+		match gpg::run(&app, &options, &[], logger()).await {
+			Ok(()) if ok => {}
+			Err(OkcError::Protocol(msg)) if !ok => assert!(msg.contains("protocol version"), "{}", msg),
+			res => panic!("unexpected result for {:?} (strict: {}): {:?}", version, strict_version, res),
+		}
+		assert!(sink.0.lock().unwrap().is_empty(), "{:?}", sink.0.lock().unwrap());
+	}
+}
+
+#[tokio::test]
+async fn app_error_status() {
+	let app = MockApp::new(Box::new(|port| finish(port, &[], 2).boxed()));
+	match gpg::run(&app, &Options::default(), &[], logger()).await {
+		Err(OkcError::App(2)) => {}
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn batch_reports_worst_status() {
+	let app = BatchApp::default();
+	let batch = vec![vec!["0".to_owned()], vec!["3".to_owned()], vec!["1".to_owned()]];
+	match gpg::run_batch(&app, &Options::default(), &batch, logger()).await {
+		Err(OkcError::App(3)) => {}
+		res => panic!("unexpected result: {:?}", res),
+	}
+	assert_eq!(app.calls.load(Ordering::SeqCst), 3);
+	gpg::run_batch(&app, &Options::default(), &batch[..1], logger()).await.unwrap();
+	// The highest exit status wins, whether it is the app's or that of a local error.
+	let batch = vec![vec!["2".to_owned()], vec!["unreachable".to_owned()]];
+	match gpg::run_batch(&app, &Options::default(), &batch, logger()).await {
+		Err(OkcError::App(2)) => {}
+		res => panic!("unexpected result: {:?}", res),
+	}
+	let batch = vec![vec!["first".to_owned()], vec!["second".to_owned()], vec!["1".to_owned()]];
+	match gpg::run_batch(&app, &Options::default(), &batch, logger()).await {
+		Err(OkcError::Other(msg)) => assert_eq!(msg, "first"),
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn batch_runs_concurrently() {
+	// Only answers once every operation has sent its broadcast, which they can't if they run one at a time.
+	#[derive(Default)]
+	struct WaitingApp {
+		ports: Mutex<Vec<u16>>,
+	}
+	impl Broadcaster for WaitingApp {
+		fn send<'a>(&'a self, port: u16, _args: &'a [String]) -> BoxFuture<'a, Result<(), OkcError>> {
+			let mut ports = self.ports.lock().unwrap();
+			ports.push(port);
+			if ports.len() == 3 {
+				for port in ports.iter().copied() {
+					tokio::spawn(finish(port, &[], 0));
+				}
+			}
+			Box::pin(async { Ok(()) })
+		}
+	}
+	let app = WaitingApp::default();
+	let batch = vec![Vec::new(); 3];
+	let options = Options::default();
+	let run = gpg::run_batch(&app, &options, &batch, logger());
+	tokio::time::timeout(std::time::Duration::from_secs(5), run).await.expect("the operations didn't overlap").unwrap();
+	let options = Options { bind_port: 1, ..Options::default() };
+	match gpg::run_batch(&app, &options, &batch, logger()).await {
+		Err(OkcError::Other(msg)) => assert!(msg.contains("OKC_BIND_PORT"), "{}", msg),
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn batch_writes_one_result() {
+	let dir = temp_dir("batch_writes_one_result");
+	let result = dir.join("result.json");
+	let app = BatchApp::default();
+	let batch = vec![vec!["0".to_owned()], vec!["3".to_owned()]];
+	let options = Options { result_json: Some(result.to_str().unwrap().to_owned()), ..Options::default() };
+	assert!(gpg::run_batch(&app, &options, &batch, logger()).await.is_err());
+	let json = std::fs::read_to_string(&result).unwrap();
+	assert_eq!(json.lines().count(), 1, "{}", json);
+	assert!(json.starts_with(r#"{"success":false,"status":3,"#), "{}", json);
+	let operations = &json[json.find(r#""operations":[{"success":true,"status":0,"#).expect(&json)..];
+	assert!(operations.contains(r#"},{"success":false,"status":3,"#), "{}", json);
+}
+
+#[tokio::test]
+async fn retryable_statuses_are_retried() {
+	// Answers with the statuses in turn, then with success.
+	let app = |statuses: &'static [u8]| {
+		let attempts = Arc::new(AtomicUsize::new(0));
+		MockApp::new(Box::new(move |port| {
+			let status = statuses.get(attempts.fetch_add(1, Ordering::SeqCst)).copied().unwrap_or(0);
+			finish(port, &[], status).boxed()
+		}))
+	};
+	let mut options = Options::default();
+	options.retry.retries = 2;
+	options.retry.statuses = vec![5];
+	options.retry.backoff.base = std::time::Duration::from_millis(10);
+	let cases: [(&'static [u8], Option<u8>, usize); 3] = [(&[5, 5], None, 3), (&[5, 5, 5], Some(5), 3), (&[5, 3], Some(3), 2)];
+	for (statuses, error, calls) in cases.iter().copied() {
+		let app = app(statuses);
+		match (gpg::run_with_retries(&app, &options, &[], logger()).await, error) {
+			(Ok(()), None) => {}
+			(Err(OkcError::App(status)), Some(error)) => assert_eq!(status, error),
+			(res, _) => panic!("unexpected result for {:?}: {:?}", statuses, res),
+		}
+		assert_eq!(app.calls.lock().unwrap().len(), calls, "{:?}", statuses);
+	}
+}
+
+#[tokio::test]
+async fn hooks_get_the_outcome() {
+	let dir = temp_dir("hooks_get_the_outcome");
+	let (input, report) = (dir.join("input"), dir.join("report"));
+	std::fs::write(&input, b"hello").unwrap();
+	let hook = |outcome: &str| Some(format!(
+		r#"echo "{} $OKC_STATUS $OKC_INPUT_BYTES $OKC_OUTPUT_BYTES $OKC_ERROR" > '{}'"#, outcome, report.display(),
+	));
+	let options = Options { on_success: hook("success"), on_failure: hook("failure"), ..Options::default() };
+	for status in [0, 2] {
+		let input_path = input.to_str().unwrap().to_owned();
+		let app = MockApp::new(Box::new(move |port| {
+			let input = input_path.clone();
+			async move {
+				read_input(port, &[1], &input).await;
+				finish(port, &[], status).await;
+			}.boxed()
+		}));
+		let res = gpg::run_with_retries(&app, &options, &[], logger()).await;
+		assert_eq!(res.is_ok(), status == 0);
+		let expected = match status {
+			0 => "success 0 5 0 \n".to_owned(),
+			_ => format!("failure 2 5 0 {}\n", res.unwrap_err()),
+		};
+		assert_eq!(std::fs::read_to_string(&report).unwrap(), expected);
+	}
+}
+
+/// Answers each broadcast with the status code given as its only argument, or fails to send it if that isn't
+/// a number.
+#[derive(Default)]
+struct BatchApp {
+	calls: AtomicUsize,
+}
+
+impl Broadcaster for BatchApp {
+	fn send<'a>(&'a self, port: u16, args: &'a [String]) -> BoxFuture<'a, Result<(), OkcError>> {
+		self.calls.fetch_add(1, Ordering::SeqCst);
+		let res = match args[0].parse() {
+			Ok(status) => {
+				tokio::spawn(finish(port, &[], status));
+				Ok(())
+			}
+			Err(_) => Err(OkcError::Other(args[0].clone())),
+		};
+		Box::pin(async { res })
+	}
+}
+
+/// Only answers the broadcast if `answers` is set, and the fallback otherwise.
+struct FallbackApp {
+	answers: bool,
+	fallbacks: AtomicUsize,
+}
+
+impl Broadcaster for FallbackApp {
+	fn send<'a>(&'a self, port: u16, _args: &'a [String]) -> BoxFuture<'a, Result<(), OkcError>> {
+		if self.answers {
+			tokio::spawn(finish(port, &[], 0));
+		}
+		Box::pin(async { Ok(()) })
+	}
+
+	fn fallback<'a>(&'a self, port: u16, _args: &'a [String]) -> BoxFuture<'a, Result<bool, OkcError>> {
+		self.fallbacks.fetch_add(1, Ordering::SeqCst);
+		if !self.answers {
+			tokio::spawn(finish(port, &[], 0));
+		}
+		Box::pin(async { Ok(true) })
+	}
+}
+
+#[tokio::test]
+async fn fallback_when_the_app_does_not_connect() {
+	for answers in [false, true] {
+		let app = FallbackApp { answers, fallbacks: AtomicUsize::new(0) };
+		let start = std::time::Instant::now();
+		gpg::run(&app, &Options::default(), &[], logger()).await.unwrap();
+		assert_eq!(app.fallbacks.load(Ordering::SeqCst), if answers { 0 } else { 1 });
+		assert_eq!(start.elapsed() >= gpg::FALLBACK_DELAY, !answers, "{:?}", start.elapsed());
+	}
+}
+
+#[tokio::test]
+async fn detached_signature() {
+	let dir = temp_dir("detached_signature");
+	let input = dir.join("data").to_str().unwrap().to_owned();
+	let signature = dir.join("data.sig").to_str().unwrap().to_owned();
+	let output = dir.join("output").to_str().unwrap().to_owned();
+	std::fs::write(&input, b"signed data").unwrap();
+	let paths = (input.clone(), signature.clone(), output.clone());
+	let app = MockApp::new(Box::new(move |port| {
+		let (input, signature, output) = paths.clone();
+		async move {
+			let data = read_input(port, &[3, 0], &input).await;
+			assert_eq!(data, b"signed data");
+			let sig = write_output(port, &[3, 3], &signature, b"binary signature");
+			let out = write_output(port, &[3, 1], &output, b"primary output");
+			futures_util::future::join(sig, out).await;
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let args = vec!["--detach-sign".to_owned(), input.clone()];
+	gpg::run(&app, &Options::default(), &args, logger()).await.unwrap();
+	assert_eq!(std::fs::read(&input).unwrap(), b"signed data");
+	assert_eq!(std::fs::read(&signature).unwrap(), b"binary signature");
+	assert_eq!(std::fs::read(&output).unwrap(), b"primary output");
+}
+
+#[tokio::test]
+async fn wait_output_after_control() {
+	let dir = temp_dir("wait_output_after_control");
+	let output = dir.join("output").to_str().unwrap().to_owned();
+	let output_path = output.clone();
+	let app = MockApp::new(Box::new(move |port| {
+		let output = output_path.clone();
+		async move {
+			let mut stream = connect(port, &[2]).await;
+			send_str(&mut stream, &output).await;
+			write_frames(&mut stream, b"first ").await.unwrap();
+			stream.flush().await.unwrap();
+			// Let okc-gpg start writing before the control connection reports success.
+			tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+			finish(port, &[], 0).await;
+			tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+			write_frames(&mut stream, b"second").await.unwrap();
+			stream.write_u16(0).await.unwrap();
+		}.boxed()
+	}));
+	let options = Options { wait_output: true, ..Options::default() };
+	gpg::run(&app, &options, &[], logger()).await.unwrap();
+	assert_eq!(std::fs::read(&output).unwrap(), b"first second");
+}
+
+#[tokio::test]
+async fn any_connection_order() {
+	let orders = [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+	for order in orders.iter().copied() {
+		let dir = temp_dir(&format!("any_connection_order-{:?}", order));
+		let input = dir.join("input").to_str().unwrap().to_owned();
+		let output = dir.join("output").to_str().unwrap().to_owned();
+		std::fs::write(&input, b"hello world").unwrap();
+		let paths = (input.clone(), output.clone());
+		let app = MockApp::new(Box::new(move |port| {
+			let (input, output) = paths.clone();
+			async move {
+				let mut streams = [None, None, None];
+				for kind in order {
+					let mut stream = connect(port, &[kind as u8]).await;
+					match kind {
+						1 => send_str(&mut stream, &input).await,
+						2 => send_str(&mut stream, &output).await,
+						_ => {}
+					}
+					streams[kind] = Some(stream);
+				}
+				// Report success before any data has been transferred.
+				let mut control = streams[0].take().unwrap();
+				send_str(&mut control, "").await;
+				control.write_u8(0).await.unwrap();
+				let data = read_frames(streams[1].as_mut().unwrap()).await;
+				let output = streams[2].as_mut().unwrap();
+				write_frames(output, &data.to_ascii_uppercase()).await.unwrap();
+				output.write_u16(0).await.unwrap();
+				output.read_to_end(&mut Vec::new()).await.unwrap();
+			}.boxed()
+		}));
+		gpg::run(&app, &Options::default(), &[], logger()).await.unwrap();
+		assert_eq!(std::fs::read(&output).unwrap(), b"HELLO WORLD", "order {:?}", order);
+	}
+}
+
+#[tokio::test]
+async fn duplicate_control_connection_is_rejected() {
+	let app = MockApp::new(Box::new(|port| async move {
+		let mut control = connect(port, &[0]).await;
+		tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+		// The second control connection is closed without its status being taken.
+		let mut duplicate = connect(port, &[0]).await;
+		let _ = duplicate.write_all(&[0, 0, 3]).await;
+		let mut rest = Vec::new();
+		let _ = duplicate.read_to_end(&mut rest).await;
+		assert!(rest.is_empty());
+		send_str(&mut control, "").await;
+		control.write_u8(0).await.unwrap();
+	}.boxed()));
+	gpg::run(&app, &Options::default(), &[], logger()).await.unwrap();
+}
+
+#[tokio::test]
+async fn data_error_after_success() {
+	let dir = temp_dir("data_error_after_success");
+	let output = dir.join("missing").join("output").to_str().unwrap().to_owned();
+	let app = MockApp::new(Box::new(move |port| {
+		let output = output.clone();
+		async move {
+			let mut stream = connect(port, &[2]).await;
+			finish(port, &[], 0).await;
+			send_str(&mut stream, &output).await;
+			let _ = write_frames(&mut stream, b"data").await;
+			let _ = stream.write_u16(0).await;
+		}.boxed()
+	}));
+	match gpg::run(&app, &Options::default(), &[], logger()).await {
+		Err(OkcError::Other(msg)) => assert!(msg.contains("after the app reported success"), "{}", msg),
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn data_error_before_success() {
+	let dir = temp_dir("data_error_before_success");
+	let output = dir.join("missing").join("output").to_str().unwrap().to_owned();
+	let app = MockApp::new(Box::new(move |port| {
+		let output = output.clone();
+		async move {
+			let mut stream = connect(port, &[2]).await;
+			send_str(&mut stream, &output).await;
+			let _ = write_frames(&mut stream, b"data").await;
+			let _ = stream.write_u16(0).await;
+			let _ = stream.read_to_end(&mut Vec::new()).await;
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	match gpg::run(&app, &Options::default(), &[], logger()).await {
+		Err(OkcError::Other(msg)) => assert!(msg.contains("before the app reported success"), "{}", msg),
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn silent_connections_are_dropped() {
+	let app = MockApp::new(Box::new(|port| async move {
+		// Holds the only connection slot until it's dropped for not sending its type.
+		let mut silent = connect(port, &[]).await;
+		let mut rest = Vec::new();
+		assert_eq!(silent.read_to_end(&mut rest).await.unwrap(), 0);
+		finish(port, &[], 0).await;
+	}.boxed()));
+	let limits = Limits {
+		max_connections: 1, handshake_timeout: std::time::Duration::from_millis(100), ..Limits::default()
+	};
+	let options = Options { limits, ..Options::default() };
+	let start = std::time::Instant::now();
+	gpg::run(&app, &options, &[], logger()).await.unwrap();
+	assert!(start.elapsed() < std::time::Duration::from_secs(5), "{:?}", start.elapsed());
+}
+
+#[tokio::test]
+async fn connection_deadline() {
+	let dir = temp_dir("connection_deadline");
+	let output = dir.join("output").to_str().unwrap().to_owned();
+	let app = MockApp::new(Box::new(move |port| {
+		let output = output.clone();
+		async move {
+			let mut stream = connect(port, &[2]).await;
+			send_str(&mut stream, &output).await;
+			finish(port, &[], 0).await;
+			// Keep making progress, just too slowly.
+			loop {
+				if write_frames(&mut stream, b"x").await.is_err() {
+					break;
+				}
+				tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+			}
+		}.boxed()
+	}));
+	let limits = Limits { max_connection_duration: Some(std::time::Duration::from_millis(200)), ..Limits::default() };
+	let options = Options { limits, ..Options::default() };
+	match gpg::run(&app, &options, &[], logger()).await {
+		Err(OkcError::Timeout(msg)) => assert!(msg.contains("output connection 1 took longer"), "{}", msg),
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+/// Accepts at most a few bytes per write and makes every other call wait, like a slow or full pipe.
+struct ThrottledWriter {
+	data: Vec<u8>,
+	max_write: usize,
+	ready: bool,
+}
+
+impl AsyncWrite for ThrottledWriter {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+		self.ready = !self.ready;
+		if !self.ready {
+			cx.waker().wake_by_ref();
+			return Poll::Pending;
+		}
+		let len = buf.len().min(self.max_write);
+		self.data.extend_from_slice(&buf[..len]);
+		Poll::Ready(Ok(len))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+}
+
+async fn frames(data: &[u8]) -> Vec<u8> {
+	let mut framed = Vec::new();
+	write_frames(&mut framed, data).await.unwrap();
+	framed.extend_from_slice(&[0, 0]);
+	framed
+}
+
+#[tokio::test]
+async fn throttled_output_is_complete() {
+	let data = (0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+	let framed = frames(&data).await;
+	let mut writer = ThrottledWriter { data: Vec::new(), max_write: 7, ready: false };
+	let limits = Limits { chunk_size: 1000, ..Limits::default() };
+	let copied = gpg::copy_output(&mut &framed[..], &mut writer, "test", Transfer::plain(&limits), &logger()).await.unwrap();
+	assert_eq!(copied.len, data.len() as u64);
+	assert!(writer.data == data);
+}
+
+#[tokio::test]
+async fn zero_length_write_is_an_error() {
+	let framed = frames(b"data").await;
+	let mut writer = ThrottledWriter { data: Vec::new(), max_write: 0, ready: false };
+	let limits = Limits::default();
+	match gpg::copy_output(&mut &framed[..], &mut writer, "test", Transfer::plain(&limits), &logger()).await {
+		Err(OkcError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::WriteZero),
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn max_bytes_is_enforced() {
+	let framed = frames(&[0; 100]).await;
+	let limits = Limits { max_bytes: Some(150), ..Limits::default() };
+	let used = std::sync::atomic::AtomicU64::new(60);
+	let transfer = Transfer { used: Some(&used), ..Transfer::plain(&limits) };
+	match gpg::copy_output(&mut &framed[..], &mut Vec::new(), "test", transfer, &logger()).await {
+		Err(OkcError::Other(msg)) => assert!(msg.contains("limit of 150 bytes"), "{}", msg),
+		res => panic!("unexpected result: {:?}", res),
+	}
+	used.store(50, Ordering::SeqCst);
+	gpg::copy_output(&mut &framed[..], &mut Vec::new(), "test", transfer, &logger()).await.unwrap();
+}
+
+#[tokio::test]
+async fn max_bytes_is_enforced_on_inflated_output() {
+	// 64 MiB of zeros compress to a few frames, which must not be inflated all at once.
+	let mut deflater = Deflater::new();
+	let mut compressed = Vec::new();
+	deflater.compress(&vec![0; 64 << 20], &mut compressed);
+	deflater.finish(&mut compressed);
+	let framed = frames(&compressed).await;
+	let limits = Limits { max_bytes: Some(1 << 20), ..Limits::default() };
+	let used = std::sync::atomic::AtomicU64::new(0);
+	let transfer = Transfer { compressed: true, used: Some(&used), ..Transfer::plain(&limits) };
+	let mut written = Vec::new();
+	match gpg::copy_output(&mut &framed[..], &mut written, "test", transfer, &logger()).await {
+		Err(OkcError::Other(msg)) => assert!(msg.contains("limit of 1048576 bytes"), "{}", msg),
+		res => panic!("unexpected result: {:?}", res),
+	}
+	assert!(written.len() <= 1 << 20, "{} bytes written", written.len());
+}
+
+#[tokio::test]
+async fn output_checksum() {
+	let framed = frames(b"123456789").await;
+	let mut writer = ThrottledWriter { data: Vec::new(), max_write: 4, ready: false };
+	let limits = Limits::default();
+	let transfer = Transfer { checksum: true, ..Transfer::plain(&limits) };
+	let copied = gpg::copy_output(&mut &framed[..], &mut writer, "test", transfer, &logger()).await.unwrap();
+	assert_eq!(copied.checksum, Some(0xcbf43926));
+}
+
+#[tokio::test]
+async fn checksum_mismatch_is_an_error() {
+	let dir = temp_dir("checksum_mismatch_is_an_error");
+	let input = dir.join("input").to_str().unwrap().to_owned();
+	let output = dir.join("output").to_str().unwrap().to_owned();
+	std::fs::write(&input, b"123456789").unwrap();
+	let paths = (input.clone(), output.clone());
+	let app = MockApp::new(Box::new(move |port| {
+		let (input, output) = paths.clone();
+		async move {
+			read_input(port, &[1], &input).await;
+			write_output(port, &[2], &output, b"123456789").await;
+			let good = format!("[S] 0 cbf43926 {}", input);
+			let bad = format!("[S] 1 cbf43927 {}", output);
+			finish(port, &[&good, &bad], 0).await;
+		}.boxed()
+	}));
+	let options = Options { checksum: true, ..Options::default() };
+	match gpg::run(&app, &options, &[], logger()).await {
+		Err(OkcError::Other(msg)) => assert!(msg.contains("checksum mismatch"), "{}", msg),
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn checksum_before_transfer() {
+	let dir = temp_dir("checksum_before_transfer");
+	let input = dir.join("input").to_str().unwrap().to_owned();
+	std::fs::write(&input, b"123456789").unwrap();
+	// The checksum is compared once the transfer finishes, or fails the run if it never does.
+	let cases = [("cbf43926", true, None), ("cbf43927", true, Some("checksum mismatch")), ("cbf43926", false, Some("no such transfer"))];
+	for (crc, transfer, error) in cases.iter().copied() {
+		let input_path = input.clone();
+		let app = MockApp::new(Box::new(move |port| {
+			let input = input_path.clone();
+			async move {
+				let mut stream = match transfer {
+					true => Some(connect(port, &[1]).await),
+					false => None,
+				};
+				if let Some(ref mut stream) = stream {
+					send_str(stream, &input).await;
+				}
+				finish(port, &[&format!("[S] 0 {} {}", crc, input)], 0).await;
+				if let Some(ref mut stream) = stream {
+					assert_eq!(read_frames(stream).await, b"123456789");
+				}
+			}.boxed()
+		}));
+		let options = Options { checksum: true, ..Options::default() };
+		match (gpg::run(&app, &options, &[], logger()).await, error) {
+			(Ok(()), None) => {}
+			(Err(OkcError::Other(msg)), Some(error)) => assert!(msg.contains(error), "{}", msg),
+			(res, _) => panic!("unexpected result for {}: {:?}", crc, res),
+		}
+	}
+}
+
+#[tokio::test]
+async fn input_ranges() {
+	let dir = temp_dir("input_ranges");
+	let input = dir.join("input").to_str().unwrap().to_owned();
+	std::fs::write(&input, b"0123456789").unwrap();
+	let input_path = input.clone();
+	let app = MockApp::new(Box::new(move |port| {
+		let input = input_path.clone();
+		async move {
+			let ranges = [(2, 3, &b"234"[..]), (7, u64::MAX, &b"789"[..]), (20, 5, &b""[..])];
+			for (offset, len, expected) in ranges.iter() {
+				let mut stream = connect(port, &[OP_INPUT | OP_FLAG_RANGE]).await;
+				send_str(&mut stream, &input).await;
+				stream.write_u64(*offset).await.unwrap();
+				stream.write_u64(*len).await.unwrap();
+				assert_eq!(read_frames(&mut stream).await, *expected);
+			}
+			// Stdin can't seek, the connection is reset instead.
+			let mut stream = connect(port, &[OP_INPUT | OP_FLAG_RANGE]).await;
+			send_str(&mut stream, "-").await;
+			stream.write_u64(0).await.unwrap();
+			stream.write_u64(1).await.unwrap();
+			assert!(stream.read_u16().await.is_err());
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let options = Options { input_ranges: true, ..Options::default() };
+	match gpg::run(&app, &options, &[], logger()).await {
+		Err(OkcError::Other(msg)) => assert!(msg.contains("can't seek"), "{}", msg),
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn input_pull() {
+	let dir = temp_dir("input_pull");
+	let input = dir.join("input").to_str().unwrap().to_owned();
+	std::fs::write(&input, b"0123456789").unwrap();
+	let input_path = input.clone();
+	let app = MockApp::new(Box::new(move |port| {
+		let input = input_path.clone();
+		async move {
+			let mut stream = connect(port, &[OP_INPUT | OP_FLAG_PULL]).await;
+			send_str(&mut stream, &input).await;
+			stream.write_u32(4).await.unwrap();
+			let mut data = vec![0; stream.read_u16().await.unwrap() as usize];
+			stream.read_exact(&mut data).await.unwrap();
+			assert_eq!(data, b"0123");
+			// Nothing more is sent until the app asks for it.
+			let idle = tokio::time::timeout(std::time::Duration::from_millis(100), stream.read_u16()).await;
+			assert!(idle.is_err());
+			stream.write_u32(100).await.unwrap();
+			assert_eq!(read_frames(&mut stream).await, b"456789");
+			// A credit of 0 ends the stream right away.
+			let mut stream = connect(port, &[OP_INPUT | OP_FLAG_PULL]).await;
+			send_str(&mut stream, &input).await;
+			stream.write_u32(0).await.unwrap();
+			assert_eq!(read_frames(&mut stream).await, b"");
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let options = Options { input_pull: true, ..Options::default() };
+	gpg::run(&app, &options, &[], logger()).await.unwrap();
+}
+
+#[tokio::test]
+async fn input_error_resets_connection() {
+	let dir = temp_dir("input_error_resets_connection");
+	let missing = dir.join("missing").to_str().unwrap().to_owned();
+	let app = MockApp::new(Box::new(move |port| {
+		let missing = missing.clone();
+		async move {
+			let mut stream = connect(port, &[1]).await;
+			send_str(&mut stream, &missing).await;
+			let reset = match stream.read_u16().await {
+				Err(e) => e.kind() == std::io::ErrorKind::ConnectionReset,
+				Ok(_) => false,
+			};
+			finish(port, &[], if reset { 0 } else { 1 }).await;
+		}.boxed()
+	}));
+	// The failed input fails the run even though the app reports success.
+	match gpg::run(&app, &Options::default(), &[], logger()).await {
+		Err(OkcError::Other(msg)) => assert!(msg.contains("before the app reported success"), "{}", msg),
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn windows_paths_are_rejected() {
+	let dir = temp_dir("windows_paths_are_rejected");
+	let app = MockApp::new(Box::new(move |port| async move {
+		let mut stream = connect(port, &[1]).await;
+		send_str(&mut stream, "\\\\server\\share\\input.txt").await;
+		assert_eq!(stream.read_u16().await.unwrap_err().kind(), std::io::ErrorKind::ConnectionReset);
+		let mut stream = connect(port, &[2]).await;
+		send_str(&mut stream, "C:\\Users\\alice\\output.txt").await;
+		let mut rest = Vec::new();
+		stream.read_to_end(&mut rest).await.unwrap();
+		finish(port, &[], 0).await;
+	}.boxed()));
+	let options = Options { workdir: Some(dir.clone()), ..Options::default() };
+	match gpg::run(&app, &options, &[], logger()).await {
+		Err(OkcError::Other(msg)) => assert!(msg.contains("Windows network path"), "{}", msg),
+		res => panic!("unexpected result: {:?}", res),
+	}
+	assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn output_on_a_directory_fails() {
+	let dir = temp_dir("output_on_a_directory_fails");
+	let output = dir.to_str().unwrap().to_owned();
+	let app = MockApp::new(Box::new(move |port| {
+		let output = output.clone();
+		async move {
+			let mut stream = connect(port, &[2]).await;
+			send_str(&mut stream, &output).await;
+			let _ = write_frames(&mut stream, b"data").await;
+			let _ = stream.write_u16(0).await;
+			let _ = stream.read_to_end(&mut Vec::new()).await;
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	match gpg::run(&app, &Options::default(), &[], logger()).await {
+		Err(OkcError::Other(msg)) => assert!(msg.contains("is a directory"), "{}", msg),
+		res => panic!("unexpected result: {:?}", res),
+	}
+	assert!(std::fs::metadata(&dir).unwrap().is_dir());
+}
+
+#[tokio::test]
+async fn symlinked_input_is_refused() {
+	let dir = temp_dir("symlinked_input_is_refused");
+	std::fs::write(dir.join("target"), b"secret").unwrap();
+	std::os::unix::fs::symlink(dir.join("target"), dir.join("link")).unwrap();
+	let link = dir.join("link").to_str().unwrap().to_owned();
+	let app = MockApp::new(Box::new(move |port| {
+		let link = link.clone();
+		async move {
+			let mut stream = connect(port, &[1]).await;
+			send_str(&mut stream, &link).await;
+			let mut data = Vec::new();
+			let _ = stream.read_to_end(&mut data).await;
+			assert!(data.is_empty());
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let options = Options { no_follow: true, ..Options::default() };
+	match gpg::run(&app, &options, &[], logger()).await {
+		Err(OkcError::Other(msg)) => assert!(msg.contains("refusing to follow symlink"), "{}", msg),
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn paths_outside_the_root_are_rejected() {
+	let dir = std::fs::canonicalize(temp_dir("paths_outside_the_root_are_rejected")).unwrap();
+	let (root, outside) = (dir.join("root"), dir.join("outside"));
+	std::fs::create_dir(&root).unwrap();
+	std::fs::create_dir(&outside).unwrap();
+	std::fs::write(outside.join("secret"), b"secret").unwrap();
+	std::os::unix::fs::symlink(outside.join("secret"), root.join("link")).unwrap();
+	std::os::unix::fs::symlink(outside.join("new"), root.join("dangling")).unwrap();
+	let outside_path = outside.join("new").to_str().unwrap().to_owned();
+	let cases = [
+		(1, "../outside/secret".to_owned(), "outside of"),
+		(2, "../outside/new".to_owned(), "outside of"),
+		(2, outside_path, "outside of"),
+		(1, "link".to_owned(), "outside of"),
+		(2, "dangling".to_owned(), "dangling symlink"),
+	];
+	for (op, path, error) in cases.iter().cloned() {
+		let requested = path.clone();
+		let app = MockApp::new(Box::new(move |port| {
+			let path = requested.clone();
+			async move {
+				let mut stream = connect(port, &[op]).await;
+				send_str(&mut stream, &path).await;
+				// An empty output, so that nothing hangs if the path is accepted.
+				if op == 2 {
+					let _ = stream.write_u16(0).await;
+				}
+				let _ = stream.read_to_end(&mut Vec::new()).await;
+				finish(port, &[], 0).await;
+			}.boxed()
+		}));
+		let options = Options { path_root: Some(root.clone()), workdir: Some(root.clone()), ..Options::default() };
+		match gpg::run(&app, &options, &[], logger()).await {
+			Err(OkcError::Other(msg)) => assert!(msg.contains(error), "{:?}: {}", path, msg),
+			res => panic!("unexpected result for {:?}: {:?}", path, res),
+		}
+	}
+	assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn output_named_after_original_filename() {
+	let dir = temp_dir("output_named_after_original_filename");
+	let requested = dir.join("requested").to_str().unwrap().to_owned();
+	let requested_path = requested.clone();
+	let app = MockApp::new(Box::new(move |port| {
+		let requested = requested_path.clone();
+		async move {
+			let mut stream = connect(port, &[2 | 0x40]).await;
+			send_str(&mut stream, &requested).await;
+			send_str(&mut stream, "../elsewhere/report.txt").await;
+			write_frames(&mut stream, b"decrypted").await.unwrap();
+			stream.write_u16(0).await.unwrap();
+			let mut rest = Vec::new();
+			stream.read_to_end(&mut rest).await.unwrap();
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let template = dir.join("%f.dec").to_str().unwrap().to_owned();
+	let options = Options { output_template: Some(template), ..Options::default() };
+	gpg::run(&app, &options, &[], logger()).await.unwrap();
+	assert_eq!(std::fs::read(dir.join("report.txt.dec")).unwrap(), b"decrypted");
+	assert!(!std::path::Path::new(&requested).exists());
+}
+
+#[tokio::test]
+async fn output_fan_out() {
+	let dir = temp_dir("output_fan_out");
+	let output = dir.join("output").to_str().unwrap().to_owned();
+	let extras = vec![dir.join("copy1").to_str().unwrap().to_owned(), dir.join("copy2").to_str().unwrap().to_owned()];
+	let output_path = output.clone();
+	let app = MockApp::new(Box::new(move |port| {
+		let output = output_path.clone();
+		async move {
+			write_output(port, &[2], &output, &vec![7u8; 100_000]).await;
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let options = Options { output_extra: extras.clone(), ..Options::default() };
+	gpg::run(&app, &options, &[], logger()).await.unwrap();
+	for path in std::iter::once(&output).chain(extras.iter()) {
+		assert_eq!(std::fs::read(path).unwrap(), vec![7u8; 100_000], "{}", path);
+	}
+}
+
+#[tokio::test]
+async fn resume_after_app_restart() {
+	let dir = temp_dir("resume_after_app_restart");
+	let input = dir.join("input").to_str().unwrap().to_owned();
+	let output = dir.join("output").to_str().unwrap().to_owned();
+	std::fs::write(&input, b"hello").unwrap();
+	let attempts = Arc::new(AtomicUsize::new(0));
+	let (input_path, output_path, attempts_seen) = (input.clone(), output.clone(), attempts.clone());
+	let app = MockApp::new(Box::new(move |port| {
+		let (input, output, attempt) = (input_path.clone(), output_path.clone(), attempts_seen.fetch_add(1, Ordering::SeqCst));
+		async move {
+			if attempt == 0 {
+				// Killed after opening the control connection and asking for the input.
+				let control = connect(port, &[0]).await;
+				let mut stream = connect(port, &[1]).await;
+				send_str(&mut stream, &input).await;
+				stream.read_u16().await.unwrap();
+				return drop((control, stream));
+			}
+			let data = read_input(port, &[1], &input).await;
+			write_output(port, &[2], &output, &data).await;
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let (result, manifest) = (dir.join("result.json"), dir.join("manifest.json"));
+	let mut options = Options {
+		result_json: Some(result.to_str().unwrap().to_owned()),
+		manifest: Some(manifest.to_str().unwrap().to_owned()),
+		..Options::default()
+	};
+	options.retry.resumes = 1;
+	options.retry.backoff.base = std::time::Duration::from_millis(10);
+	gpg::run_with_retries(&app, &options, &[], logger()).await.unwrap();
+	assert_eq!(app.calls.lock().unwrap().len(), 2);
+	assert_eq!(std::fs::read(&output).unwrap(), b"hello");
+	// Only the connections of the app instance that finished are reported.
+	let json = std::fs::read_to_string(&result).unwrap();
+	assert!(json.contains(r#""input_bytes":5,"output_bytes":5,"#), "{}", json);
+	assert!(json.contains(r#""connections":3,"#), "{}", json);
+	let phases = &json[json.find(r#""phases":"#).expect(&json)..];
+	assert!(phases.contains(r#""connections":[{"id":3,"#) && !phases.contains(r#""id":2,"#), "{}", json);
+	let manifest = std::fs::read_to_string(&manifest).unwrap();
+	assert_eq!(manifest.matches(r#""role":"input""#).count(), 1, "{}", manifest);
+	options.result_json = None;
+	options.manifest = None;
+
+	// Once output has been written, starting over could duplicate it.
+	let output_path = output.clone();
+	let app = MockApp::new(Box::new(move |port| {
+		let output = output_path.clone();
+		async move {
+			let mut stream = connect(port, &[2]).await;
+			send_str(&mut stream, &output).await;
+			write_frames(&mut stream, b"partial").await.unwrap();
+		}.boxed()
+	}));
+	assert!(gpg::run(&app, &options, &[], logger()).await.is_err());
+	assert_eq!(app.calls.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn resume_after_a_truncated_message() {
+	let attempts = Arc::new(AtomicUsize::new(0));
+	let attempts_seen = attempts.clone();
+	let app = MockApp::new(Box::new(move |port| {
+		let attempt = attempts_seen.fetch_add(1, Ordering::SeqCst);
+		async move {
+			if attempt == 0 {
+				// Killed halfway through sending a message.
+				let mut control = connect(port, &[0]).await;
+				control.write_u16(12).await.unwrap();
+				control.write_all(b"[W] car").await.unwrap();
+				return drop(control);
+			}
+			finish(port, &["[W] careful"], 0).await;
+		}.boxed()
+	}));
+	let mut options = Options::default();
+	options.retry.resumes = 1;
+	options.retry.backoff.base = std::time::Duration::from_millis(10);
+	gpg::run(&app, &options, &[], logger()).await.unwrap();
+	assert_eq!(app.calls.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn app_messages_on_stdout() {
+	let dir = temp_dir("app_messages_on_stdout");
+	let port_file = dir.join("port");
+	let child = std::process::Command::new(env!("CARGO_BIN_EXE_okc-gpg"))
+		.env("OKC_LISTEN_ONLY", &port_file)
+		.env("OKC_APP_MESSAGES", "stdout")
+		.stdin(std::process::Stdio::null())
+		.stdout(std::process::Stdio::piped())
+		.stderr(std::process::Stdio::piped())
+		.spawn().unwrap();
+	let port = wait_for_port(&port_file).await;
+	finish(port, &["[W] careful", "signed by alice", "[E] broken"], 0).await;
+	let output = tokio::task::spawn_blocking(|| child.wait_with_output().unwrap()).await.unwrap();
+	assert!(output.status.success());
+	assert_eq!(String::from_utf8_lossy(&output.stdout), "warning: careful\nsigned by alice\n");
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(stderr.contains("broken") && !stderr.contains("careful"), "{}", stderr);
+}
+
+#[tokio::test]
+async fn app_messages_wait_for_stdout_output() {
+	let dir = temp_dir("app_messages_wait_for_stdout_output");
+	let port_file = dir.join("port");
+	let child = std::process::Command::new(env!("CARGO_BIN_EXE_okc-gpg"))
+		.env("OKC_LISTEN_ONLY", &port_file)
+		.env("OKC_APP_MESSAGES", "stdout")
+		.stdin(std::process::Stdio::null())
+		.stdout(std::process::Stdio::piped())
+		.stderr(std::process::Stdio::null())
+		.spawn().unwrap();
+	let port = wait_for_port(&port_file).await;
+	let mut stream = connect(port, &[2]).await;
+	send_str(&mut stream, "-").await;
+	write_frames(&mut stream, b"first ").await.unwrap();
+	tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+	// The message arrives halfway through the output, but is only printed after it.
+	finish(port, &["signed by alice"], 0).await;
+	tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+	write_frames(&mut stream, b"second\n").await.unwrap();
+	stream.write_u16(0).await.unwrap();
+	stream.read_to_end(&mut Vec::new()).await.unwrap();
+	let output = tokio::task::spawn_blocking(|| child.wait_with_output().unwrap()).await.unwrap();
+	assert!(output.status.success());
+	assert_eq!(String::from_utf8_lossy(&output.stdout), "first second\nsigned by alice\n");
+}
+
+#[tokio::test]
+async fn pipelined_input_and_output() {
+	let dir = temp_dir("pipelined_input_and_output");
+	let input = dir.join("input").to_str().unwrap().to_owned();
+	let output = dir.join("output").to_str().unwrap().to_owned();
+	let data = (0..1 << 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+	std::fs::write(&input, &data).unwrap();
+	let (input_path, output_path) = (input.clone(), output.clone());
+	let app = MockApp::new(Box::new(move |port| {
+		let (input, output) = (input_path.clone(), output_path.clone());
+		async move {
+			let mut rx = connect(port, &[1]).await;
+			send_str(&mut rx, &input).await;
+			let mut tx = connect(port, &[2]).await;
+			send_str(&mut tx, &output).await;
+			// Each input frame is transformed and written out before the next one is read.
+			loop {
+				let len = rx.read_u16().await.unwrap() as usize;
+				if len == 0 {
+					break;
+				}
+				let mut frame = vec![0; len];
+				rx.read_exact(&mut frame).await.unwrap();
+				write_frames(&mut tx, &frame.iter().map(|b| !b).collect::<Vec<_>>()).await.unwrap();
+			}
+			tx.write_u16(0).await.unwrap();
+			let mut rest = Vec::new();
+			tx.read_to_end(&mut rest).await.unwrap();
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let options = Options { limits: Limits { chunk_size: 4096, ..Limits::default() }, ..Options::default() };
+	let stats = gpg::Stats::default();
+	gpg::run_with_stats(&app, &options, &[], &stats, logger()).await.unwrap();
+	assert_eq!(std::fs::read(&output).unwrap(), data.iter().map(|b| !b).collect::<Vec<_>>());
+	assert_eq!(stats.input_bytes.load(Ordering::SeqCst), data.len() as u64);
+	assert_eq!(stats.output_bytes.load(Ordering::SeqCst), data.len() as u64);
+	assert_eq!(stats.connections.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn output_to_fifo() {
+	let dir = temp_dir("output_to_fifo");
+	let fifo = dir.join("fifo");
+	let c_path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+	assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+	let reader_path = fifo.clone();
+	let reader = std::thread::spawn(move || std::fs::read(reader_path).unwrap());
+	let output = fifo.to_str().unwrap().to_owned();
+	let app = MockApp::new(Box::new(move |port| {
+		let output = output.clone();
+		async move {
+			write_output(port, &[2], &output, b"through the pipe").await;
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let options = Options { verify_output: true, ..Options::default() };
+	gpg::run(&app, &options, &[], logger()).await.unwrap();
+	assert_eq!(reader.join().unwrap(), b"through the pipe");
+	assert!(std::fs::metadata(&fifo).unwrap().file_type().is_fifo());
+}
+
+#[tokio::test]
+async fn app_may_stop_reading_input() {
+	let dir = temp_dir("app_may_stop_reading_input");
+	let input = dir.join("input").to_str().unwrap().to_owned();
+	std::fs::write(&input, vec![1u8; 8 << 20]).unwrap();
+	let input_path = input.clone();
+	let app = MockApp::new(Box::new(move |port| {
+		let input = input_path.clone();
+		async move {
+			let mut stream = connect(port, &[1]).await;
+			send_str(&mut stream, &input).await;
+			let mut frame = vec![0; stream.read_u16().await.unwrap() as usize];
+			stream.read_exact(&mut frame).await.unwrap();
+			// Enough has been seen to know the outcome, the rest isn't needed.
+			finish(port, &[], 0).await;
+			drop(stream);
+		}.boxed()
+	}));
+	gpg::run(&app, &Options::default(), &[], logger()).await.unwrap();
+}
+
+#[tokio::test]
+async fn connections_wait_for_a_permit() {
+	let dir = temp_dir("connections_wait_for_a_permit");
+	let input = dir.join("input").to_str().unwrap().to_owned();
+	std::fs::write(&input, vec![1u8; 8 << 20]).unwrap();
+	let input_path = input.clone();
+	let app = MockApp::new(Box::new(move |port| {
+		let input = input_path.clone();
+		async move {
+			let mut first = connect(port, &[1]).await;
+			send_str(&mut first, &input).await;
+			let mut second = connect(port, &[1]).await;
+			send_str(&mut second, &input).await;
+			// The first connection is stuck until its data is read, so the second one isn't served yet.
+			let idle = tokio::time::timeout(std::time::Duration::from_millis(200), second.read_u16()).await;
+			assert!(idle.is_err());
+			assert_eq!(read_frames(&mut first).await.len(), 8 << 20);
+			assert_eq!(read_frames(&mut second).await.len(), 8 << 20);
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let options = Options { limits: Limits { max_connections: 1, ..Limits::default() }, ..Options::default() };
+	gpg::run(&app, &options, &[], logger()).await.unwrap();
+}
+
+#[tokio::test]
+async fn manifest_lists_touched_files() {
+	let dir = temp_dir("manifest_lists_touched_files");
+	std::fs::write(dir.join("input"), b"hello").unwrap();
+	let app = MockApp::new(Box::new(|port| async move {
+		let data = read_input(port, &[1], "input").await;
+		write_output(port, &[2], "output", &data).await;
+		finish(port, &[], 0).await;
+	}.boxed()));
+	let manifest = dir.join("manifest.json");
+	let options = Options {
+		workdir: Some(dir.clone()), manifest: Some(manifest.to_str().unwrap().to_owned()), ..Options::default()
+	};
+	gpg::run_with_retries(&app, &options, &[], logger()).await.unwrap();
+	let manifest = std::fs::read_to_string(&manifest).unwrap();
+	assert!(manifest.starts_with("{\"files\":["), "{}", manifest);
+	for role in ["input", "output"].iter() {
+		let entry = format!("{{\"role\":\"{0}\",\"path\":\"{1}/{0}\",\"bytes\":5}}", role, dir.to_str().unwrap());
+		assert!(manifest.contains(&entry), "{}", manifest);
+	}
+}
+
+#[tokio::test]
+async fn small_files_are_sent_at_once() {
+	let dir = temp_dir("small_files_are_sent_at_once");
+	let input = dir.join("input").to_str().unwrap().to_owned();
+	std::fs::write(&input, b"0123456789").unwrap();
+	let input_path = input.clone();
+	let app = MockApp::new(Box::new(move |port| {
+		let input = input_path.clone();
+		async move {
+			let mut stream = connect(port, &[1]).await;
+			send_str(&mut stream, &input).await;
+			// A single frame despite the tiny chunk size.
+			assert_eq!(stream.read_u16().await.unwrap(), 10);
+			let mut data = vec![0; 10];
+			stream.read_exact(&mut data).await.unwrap();
+			assert_eq!(data, b"0123456789");
+			assert_eq!(read_frames(&mut stream).await, b"");
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let limits = Limits { chunk_size: 4, small_file_size: 10, ..Limits::default() };
+	gpg::run(&app, &Options { limits, ..Options::default() }, &[], logger()).await.unwrap();
+}
+
+#[tokio::test]
+async fn input_arg_is_streamed_as_stdin() {
+	let dir = temp_dir("input_arg_is_streamed_as_stdin");
+	let input = dir.join("message.txt").to_str().unwrap().to_owned();
+	std::fs::write(&input, b"from the argument").unwrap();
+	let received = Arc::new(Mutex::new(Vec::new()));
+	let app_received = received.clone();
+	let app = MockApp::new(Box::new(move |port| {
+		let received = app_received.clone();
+		async move {
+			*received.lock().unwrap() = read_input(port, &[1], "-").await;
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let options = Options { input_arg: Some(-1), ..Options::default() };
+	gpg::run(&app, &options, &["--clear-sign".to_owned(), input], logger()).await.unwrap();
+	assert_eq!(*received.lock().unwrap(), b"from the argument");
+	assert_eq!(app.calls.lock().unwrap()[0].1, vec!["--clear-sign".to_owned(), "-".to_owned()]);
+}
+
+#[tokio::test]
+async fn stdin_is_streamed_in_order() {
+	let dir = temp_dir("stdin_is_streamed_in_order");
+	let port_file = dir.join("port");
+	// A counter, so any duplicated, dropped or reordered chunk shows. The chunk size doesn't divide it.
+	let data: Vec<u8> = (0..(3 << 20) / 4).flat_map(|i: u32| i.to_le_bytes()).collect();
+	let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_okc-gpg"))
+		.env("OKC_LISTEN_ONLY", &port_file)
+		.env("OKC_CHUNK_SIZE", "4093")
+		.stdin(std::process::Stdio::piped())
+		.stdout(std::process::Stdio::null())
+		.stderr(std::process::Stdio::null())
+		.spawn().unwrap();
+	let mut stdin = child.stdin.take().unwrap();
+	let sent = data.clone();
+	let writer = std::thread::spawn(move || std::io::Write::write_all(&mut stdin, &sent));
+	let port = wait_for_port(&port_file).await;
+	let received = read_input(port, &[1], "-").await;
+	finish(port, &[], 0).await;
+	writer.join().unwrap().unwrap();
+	assert!(tokio::task::spawn_blocking(move || child.wait().unwrap()).await.unwrap().success());
+	assert_eq!(received.len(), data.len());
+	assert!(received == data, "the data differs from byte {}", received.iter().zip(&data).position(|(a, b)| a != b).unwrap());
+}
+
+#[tokio::test]
+async fn status_lines_are_written_verbatim() {
+	let dir = temp_dir("status_lines_are_written_verbatim");
+	let status = dir.join("status");
+	let app = MockApp::new(Box::new(|port| {
+		finish(port, &["[V] 1", "[G] [GNUPG:] SIG_CREATED S 1 8 00", "[G] [GNUPG:] END"], 0).boxed()
+	}));
+	let sink = Arc::new(Collector::default());
+	let options = Options {
+		status_fd: Some(status.to_str().unwrap().to_owned()), warning_sink: Some(sink.clone()), ..Options::default()
+	};
+	gpg::run(&app, &options, &[], logger()).await.unwrap();
+	assert_eq!(std::fs::read_to_string(&status).unwrap(), "[GNUPG:] SIG_CREATED S 1 8 00\n[GNUPG:] END\n");
+	assert!(sink.0.lock().unwrap().is_empty());
+
+	// Apps that don't send their version predate status lines, so the prefix means nothing to them.
+	let app = MockApp::new(Box::new(|port| finish(port, &["[G] not a status"], 0).boxed()));
+	gpg::run(&app, &options, &[], logger()).await.unwrap();
+	assert_eq!(*sink.0.lock().unwrap(), vec!["[G] not a status".to_owned()]);
+}
+
+#[test]
+fn backoff_grows_up_to_the_maximum() {
+	use std::time::Duration;
+	let backoff = Backoff { base: Duration::from_millis(100), max: Duration::from_millis(350), multiplier: 2.0 };
+	let delays: Vec<_> = (1..=4).map(|attempt| backoff.delay(attempt).as_millis()).collect();
+	assert_eq!(delays, vec![100, 200, 350, 350]);
+	assert_eq!(backoff.delay(u32::MAX), Duration::from_millis(350));
+	assert_eq!(Backoff { multiplier: 1.0, ..backoff }.delay(10), Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn suggested_filename_is_reported() {
+	let dir = temp_dir("suggested_filename_is_reported");
+	let result = dir.join("result.json");
+	let app = MockApp::new(Box::new(|port| finish(port, &["[F] report \"final\".pdf"], 0).boxed()));
+	let options = Options { result_json: Some(result.to_str().unwrap().to_owned()), ..Options::default() };
+	gpg::run_with_retries(&app, &options, &[], logger()).await.unwrap();
+	let json = std::fs::read_to_string(&result).unwrap();
+	assert!(json.contains(r#""suggested_filename":"report \"final\".pdf""#), "{}", json);
+	assert!(json.contains(r#""warnings":[]"#), "{}", json);
+}
+
+/// Plays the app from within `send`, before the broadcast is even complete.
+struct EagerApp;
+
+impl Broadcaster for EagerApp {
+	fn send<'a>(&'a self, port: u16, _args: &'a [String]) -> BoxFuture<'a, Result<(), OkcError>> {
+		Box::pin(async move {
+			let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await
+				.expect("the listener must be listening before the broadcast is sent");
+			stream.write_all(&[0, 0, 0, 0]).await.unwrap();
+			Ok(())
+		})
+	}
+}
+
+#[tokio::test]
+async fn listening_before_broadcast() {
+	gpg::run(&EagerApp, &Options::default(), &[], logger()).await.unwrap();
+}
+
+#[tokio::test]
+async fn output_metadata_is_applied() {
+	use std::os::unix::fs::PermissionsExt;
+	let dir = temp_dir("output_metadata_is_applied");
+	let (output, untouched) = (dir.join("output"), dir.join("untouched"));
+	let (output_path, untouched_path) = (output.to_str().unwrap().to_owned(), untouched.to_str().unwrap().to_owned());
+	let app = MockApp::new(Box::new(move |port| {
+		let (output, untouched) = (output_path.clone(), untouched_path.clone());
+		async move {
+			for (path, mode, mtime_ms) in [(output, 0o4640, 1_500_000_000_250), (untouched, u32::MAX, u64::MAX)] {
+				let mut stream = connect(port, &[OP_OUTPUT | OP_FLAG_METADATA]).await;
+				send_str(&mut stream, &path).await;
+				stream.write_u32(mode).await.unwrap();
+				stream.write_u64(mtime_ms).await.unwrap();
+				write_frames(&mut stream, b"restored").await.unwrap();
+				stream.write_u16(0).await.unwrap();
+				stream.read_to_end(&mut Vec::new()).await.unwrap();
+			}
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let options = Options { output_metadata: true, ..Options::default() };
+	gpg::run(&app, &options, &[], logger()).await.unwrap();
+	let metadata = std::fs::metadata(&output).unwrap();
+	// The setuid bit is dropped.
+	assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+	let mtime = metadata.modified().unwrap().duration_since(std::time::UNIX_EPOCH).unwrap();
+	assert_eq!(mtime.as_millis(), 1_500_000_000_250);
+	let metadata = std::fs::metadata(&untouched).unwrap();
+	assert!(metadata.modified().unwrap().elapsed().unwrap() < std::time::Duration::from_secs(60));
+	assert_eq!(std::fs::read(&untouched).unwrap(), b"restored");
+}
+
+#[tokio::test]
+async fn phases_in_result() {
+	let dir = temp_dir("phases_in_result");
+	let (input, output, result) = (dir.join("input"), dir.join("output"), dir.join("result.json"));
+	std::fs::write(&input, b"timed").unwrap();
+	let (input_path, output_path) = (input.to_str().unwrap().to_owned(), output.to_str().unwrap().to_owned());
+	let app = MockApp::new(Box::new(move |port| {
+		let (input, output) = (input_path.clone(), output_path.clone());
+		async move {
+			let data = read_input(port, &[1], &input).await;
+			write_output(port, &[2], &output, &data).await;
+			finish(port, &[], 0).await;
+		}.boxed()
+	}));
+	let options = Options { result_json: Some(result.to_str().unwrap().to_owned()), ..Options::default() };
+	gpg::run_with_retries(&app, &options, &[], logger()).await.unwrap();
+	let json = std::fs::read_to_string(&result).unwrap();
+	let phases = &json[json.find(r#""phases":"#).expect(&json)..];
+	for phase in ["broadcast_ms", "first_connection_ms", "status_wait_ms"] {
+		let value = &phases[phases.find(phase).expect(phases) + phase.len() + 2..];
+		assert!(value.starts_with(|c: char| c.is_ascii_digit()), "{}", phases);
+	}
+	assert!(phases.contains(r#"{"id":1,"role":"input","ms":"#), "{}", phases);
+	assert!(phases.contains(r#"{"id":2,"role":"output","ms":"#), "{}", phases);
+}
+
+#[tokio::test]
+async fn reports_share_a_file_descriptor() {
+	use std::os::unix::io::AsRawFd;
+	let dir = temp_dir("reports_share_a_file_descriptor");
+	let report = std::fs::File::create(dir.join("report")).unwrap();
+	let fd = report.as_raw_fd().to_string();
+	let app = MockApp::new(Box::new(|port| finish(port, &[], 0).boxed()));
+	let options = Options { result_json: Some(fd.clone()), manifest: Some(fd), ..Options::default() };
+	gpg::run_with_retries(&app, &options, &[], logger()).await.unwrap();
+	// Writing the result must not have closed the descriptor the manifest and the caller still use.
+	std::io::Write::write_all(&mut &report, b"end\n").unwrap();
+	let reports = std::fs::read_to_string(dir.join("report")).unwrap();
+	let lines: Vec<_> = reports.lines().collect();
+	assert_eq!(lines.len(), 3, "{}", reports);
+	assert!(lines[0].starts_with("{\"success\":true,"), "{}", reports);
+	assert_eq!(lines[1], "{\"files\":[]}");
+	assert_eq!(lines[2], "end");
+}
+
+#[tokio::test]
+async fn listen_only_file_has_a_line_per_operation() {
+	let dir = temp_dir("listen_only_file_has_a_line_per_operation");
+	let ports = dir.join("ports");
+	let broadcaster = gpg::ListenOnlyBroadcaster::new(ports.to_str().unwrap().to_owned(), logger());
+	broadcaster.send(1234, &[]).await.unwrap();
+	broadcaster.send(1235, &[]).await.unwrap();
+	// A resume doesn't add another line.
+	broadcaster.send(1234, &[]).await.unwrap();
+	assert_eq!(std::fs::read_to_string(&ports).unwrap(), "1234\n1235\n");
+	broadcaster.cleanup(1234).await;
+	broadcaster.send(1236, &[]).await.unwrap();
+	assert_eq!(std::fs::read_to_string(&ports).unwrap(), "1235\n1236\n");
+}
+
+#[tokio::test]
+async fn listen_only_leaves_the_descriptor_open() {
+	use std::os::unix::io::AsRawFd;
+	let dir = temp_dir("listen_only_leaves_the_descriptor_open");
+	let ports = std::fs::File::create(dir.join("ports")).unwrap();
+	let broadcaster = gpg::ListenOnlyBroadcaster::new(ports.as_raw_fd().to_string(), logger());
+	// As on a resume, which writes the port again.
+	broadcaster.send(1234, &[]).await.unwrap();
+	broadcaster.send(1234, &[]).await.unwrap();
+	std::io::Write::write_all(&mut &ports, b"end\n").unwrap();
+	assert_eq!(std::fs::read_to_string(dir.join("ports")).unwrap(), "1234\n1234\nend\n");
+}
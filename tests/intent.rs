@@ -0,0 +1,24 @@
+use okc_agents::args::Extra;
+use okc_agents::intent::{ExtraValue, Extras};
+
+#[test]
+fn render() {
+	let mut extras = Extras::new();
+	extras.insert("a.PORT", ExtraValue::Int(1234)).unwrap()
+		.insert("a.ARGS", ExtraValue::StringArray(vec!["x,y".to_owned(), "z".to_owned()])).unwrap()
+		.insert("a.DEBUG", ExtraValue::Bool(true)).unwrap();
+	assert_eq!(extras.render(), vec!["--ei", "a.PORT", "1234", "--esa", "a.ARGS", "x\\,y,z", "--ez", "a.DEBUG", "true"]);
+	assert!(extras.insert("a.PORT", ExtraValue::Int(1)).is_err());
+	assert!(extras.insert("", ExtraValue::Int(1)).is_err());
+}
+
+#[test]
+fn typed_extras_are_validated() {
+	let extra = |flag, value: &str| Extra { key: "a.KEY".to_owned(), flag, value: value.to_owned() };
+	let mut extras = Extras::new();
+	extras.insert_extra(&extra("--el", "5000000000")).unwrap();
+	assert_eq!(extras.get("a.KEY"), Some(&ExtraValue::Long(5_000_000_000)));
+	for (flag, value) in [("--ei", "five"), ("--ef", "1.5.2"), ("--ez", "yes"), ("--eu", "x")].iter() {
+		assert!(Extras::new().insert_extra(&extra(flag, value)).is_err(), "{} {}", flag, value);
+	}
+}
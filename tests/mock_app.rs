@@ -0,0 +1,29 @@
+mod common;
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use common::*;
+
+#[test]
+fn loopback_with_mock_app() {
+	let dir = temp_dir("loopback_with_mock_app");
+	let port_file = dir.join("port");
+	let mut gpg = Command::new(env!("CARGO_BIN_EXE_okc-gpg"))
+		.env("OKC_LISTEN_ONLY", &port_file)
+		.env("OKC_APP_MESSAGES", "stderr")
+		.arg("--armor")
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn().unwrap();
+	gpg.stdin.take().unwrap().write_all(b"Hello, World!").unwrap();
+	let app = Command::new(env!("CARGO_BIN_EXE_okc-mock-app"))
+		.args(["-t", "rot13", "-w", "[W] careful", "-s", "0"])
+		.arg(&port_file).arg("-").arg("-")
+		.status().unwrap();
+	assert!(app.success());
+	let output = gpg.wait_with_output().unwrap();
+	assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+	assert_eq!(output.stdout, b"Uryyb, Jbeyq!");
+	assert!(String::from_utf8_lossy(&output.stderr).contains("careful"));
+}
@@ -0,0 +1,154 @@
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use okc_agents::proto::*;
+use okc_agents::utils::OkcError;
+
+// Deterministic xorshift generator, so failures are reproducible without extra dependencies.
+struct Rng(u64);
+
+impl Rng {
+	fn next(&mut self) -> u64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
+
+	fn bytes(&mut self, len: usize) -> Vec<u8> {
+		(0..len).map(|_| self.next() as u8).collect()
+	}
+}
+
+#[tokio::test]
+async fn listener_is_loopback_only() {
+	let listener = bind_loopback(0).await.unwrap();
+	assert!(listener.local_addr().unwrap().ip().is_loopback());
+}
+
+#[tokio::test]
+async fn fixed_port_can_be_rebound() {
+	use tokio::io::AsyncReadExt;
+	let port = bind_loopback(0).await.unwrap().local_addr().unwrap().port();
+	let listener = bind_loopback(port).await.unwrap();
+	assert_eq!(bind_loopback(port).await.unwrap_err().kind(), ErrorKind::AddrInUse);
+	// Closing the accepted end first leaves it in TIME_WAIT.
+	let mut client = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+	drop(listener.accept().await.unwrap());
+	client.read_to_end(&mut Vec::new()).await.unwrap();
+	drop(listener);
+	bind_loopback(port).await.unwrap();
+}
+
+#[tokio::test]
+async fn read_str_roundtrip() {
+	let mut stream = Vec::new();
+	write_frames(&mut stream, "hello".as_bytes()).await.unwrap();
+	assert_eq!(read_str(&mut stream.as_slice()).await.unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn truncated_frame_is_unexpected_eof() {
+	let inputs: [&[u8]; 3] = [&[], &[0], &[0, 5, b'a', b'b']];
+	for input in inputs.iter() {
+		match read_str(&mut &input[..]).await {
+			Err(OkcError::Io(e)) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+			res => panic!("unexpected result for {:?}: {:?}", input, res),
+		}
+	}
+}
+
+#[tokio::test]
+async fn invalid_message_has_context() {
+	let input = [0, 6, b'[', b'W', b']', b' ', 0xff, b'x'];
+	match read_message(&mut &input[..]).await {
+		Err(OkcError::Protocol(msg)) => {
+			assert!(msg.contains("6 bytes"), "{}", msg);
+			assert!(msg.contains("\"[W] \u{fffd}x\""), "{}", msg);
+		}
+		res => panic!("unexpected result: {:?}", res),
+	}
+	match read_str(&mut &input[..]).await {
+		Err(OkcError::Utf8(_)) => {}
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn oversized_frame_is_rejected() {
+	let input = [0x10, 0x00];
+	match read_bytes(&mut &input[..], 0x0fff).await {
+		Err(OkcError::Protocol(_)) => {}
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn arbitrary_input_respects_bound() {
+	let mut rng = Rng(0x9e3779b97f4a7c15);
+	for _ in 0..2000 {
+		let len = (rng.next() % 64) as usize;
+		let input = rng.bytes(len);
+		let max_len = (rng.next() % 32) as usize;
+		let mut rx = input.as_slice();
+		while let Ok(frame) = read_bytes(&mut rx, max_len).await {
+			assert!(frame.len() <= max_len);
+		}
+		let _ = read_str(&mut input.as_slice()).await;
+	}
+}
+
+/// Fails every other read with `Interrupted` and hands out the data one byte at a time in between.
+struct InterruptingReader {
+	data: Vec<u8>,
+	pos: usize,
+	interrupt: bool,
+}
+
+impl AsyncRead for InterruptingReader {
+	fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+		self.interrupt = !self.interrupt;
+		if self.interrupt {
+			return Poll::Ready(Err(ErrorKind::Interrupted.into()));
+		}
+		if self.pos < self.data.len() {
+			buf.put_slice(&self.data[self.pos..self.pos + 1]);
+			self.pos += 1;
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+
+#[tokio::test]
+async fn interrupted_reads_are_retried() {
+	let mut data = vec![3];
+	write_frames(&mut data, b"interrupted").await.unwrap();
+	let mut rx = InterruptingReader { data, pos: 0, interrupt: false };
+	assert_eq!(read_byte(&mut rx).await.unwrap(), 3);
+	assert_eq!(read_str(&mut rx).await.unwrap(), "interrupted");
+	match read_len(&mut rx).await {
+		Err(e) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+		res => panic!("unexpected result: {:?}", res),
+	}
+}
+
+#[tokio::test]
+async fn control_desync_is_detected() {
+	let too_long = (MAX_MESSAGE_LEN as u16 + 1).to_be_bytes();
+	let inputs: [&[u8]; 2] = [&too_long, &[0, 3, 0, 0, 0]];
+	for input in inputs.iter() {
+		match read_message(&mut &input[..]).await {
+			Err(OkcError::Protocol(msg)) => assert!(msg.contains("desynchronized"), "{}", msg),
+			res => panic!("unexpected result for {:?}: {:?}", input, res),
+		}
+	}
+	// A truncated length or message is the connection dropping, not a desync.
+	let inputs: [&[u8]; 2] = [&[0], &[0, 5, b'o', b'k', 0]];
+	for input in inputs.iter() {
+		match read_message(&mut &input[..]).await {
+			Err(OkcError::Io(e)) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+			res => panic!("unexpected result for {:?}: {:?}", input, res),
+		}
+	}
+}
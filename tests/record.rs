@@ -0,0 +1,72 @@
+mod common;
+
+use futures_util::FutureExt;
+use okc_agents::gpg::{self, Options};
+use okc_agents::record::{self, Event, ReplayBroadcaster};
+use okc_agents::utils::OkcError;
+use common::*;
+
+#[tokio::test]
+async fn replay_reproduces_session() {
+	let dir = temp_dir("replay_reproduces_session");
+	let input = dir.join("input").to_str().unwrap().to_owned();
+	let output = dir.join("output").to_str().unwrap().to_owned();
+	let recording = dir.join("recording").to_str().unwrap().to_owned();
+	std::fs::write(&input, b"recorded data").unwrap();
+	let (input_path, output_path) = (input.clone(), output.clone());
+	let app = MockApp::new(Box::new(move |port| {
+		let (input, output) = (input_path.clone(), output_path.clone());
+		async move {
+			let data = read_input(port, &[1], &input).await;
+			write_output(port, &[2], &output, &data.to_ascii_uppercase()).await;
+			finish(port, &["[W] careful"], 3).await;
+		}.boxed()
+	}));
+	let args = vec!["--decrypt".to_owned()];
+	let options = Options { record: Some(recording.clone()), record_data: true, ..Options::default() };
+	assert!(gpg::run(&app, &options, &args, logger()).await.is_err());
+	assert_eq!(std::fs::read(&output).unwrap(), b"RECORDED DATA");
+
+	let events = record::load(&recording).unwrap();
+	assert_eq!(events[0], Event::Broadcast { args: args.clone() });
+	assert!(events.contains(&Event::Status { id: 3, code: 3 }));
+	std::fs::remove_file(&output).unwrap();
+	let replay = ReplayBroadcaster { events, logger: logger() };
+	assert_eq!(replay.args(), args);
+	match gpg::run(&replay, &Options::default(), &replay.args(), logger()).await {
+		Err(OkcError::App(3)) => {}
+		res => panic!("unexpected result: {:?}", res),
+	}
+	assert_eq!(std::fs::read(&output).unwrap(), b"RECORDED DATA");
+}
+
+/// Replays `tests/fixtures/golden-session.rec` with okc-gpg itself, in `dir` as the working directory.
+fn replay_golden_session(dir: &std::path::Path) -> std::process::Output {
+	std::process::Command::new(env!("CARGO_BIN_EXE_okc-gpg"))
+		.env("OKC_REPLAY", concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/golden-session.rec"))
+		.env("OKC_WORKDIR", dir)
+		.env_remove("RUST_LOG")
+		.stdin(std::process::Stdio::null())
+		.output().unwrap()
+}
+
+// The golden session was recorded from `okc-gpg --decrypt input.txt` and `okc-mock-app -t rot13 -w
+// "[W] careful" <port> input.txt output.txt`. If this fails after a change, the protocol or what okc-gpg
+// does with it has changed: make sure that's intended, and compatible with the app, before recording it again.
+#[test]
+fn golden_session() {
+	let dir = temp_dir("golden_session");
+	std::fs::write(dir.join("input.txt"), b"golden input\n").unwrap();
+	let output = replay_golden_session(&dir);
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert_eq!(output.status.code(), Some(0), "{}", stderr);
+	assert_eq!(std::fs::read(dir.join("output.txt")).unwrap(), b"tbyqra vachg\n");
+	assert!(stderr.contains("WARN careful"), "{}", stderr);
+	assert!(!stderr.contains("failed to replay"), "{}", stderr);
+
+	// What okc-gpg sends is part of the contract too.
+	std::fs::write(dir.join("input.txt"), b"golden input!\n").unwrap();
+	let stderr = String::from_utf8_lossy(&replay_golden_session(&dir).stderr).into_owned();
+	assert!(stderr.contains("failed to replay connection 1"), "{}", stderr);
+	assert!(stderr.contains("differ from the 13 recorded ones"), "{}", stderr);
+}
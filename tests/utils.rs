@@ -0,0 +1,80 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use slog::{Drain, Logger, OwnedKVList, Record, error, o};
+use slog_async::Async;
+use okc_agents::utils::{
+	EXIT_PROTOCOL, EXIT_TIMEOUT, ExitReason, LOG_GUARD, OkcError, check_log_spec, exit_code, flush_logs,
+};
+
+#[test]
+fn exit_codes() {
+	let app = OkcError::App(2);
+	let protocol = OkcError::protocol("invalid connection type");
+	let timeout = OkcError::Timeout("am did not complete within 5 seconds".to_owned());
+	let scenarios = [
+		("success", ExitReason::Success, 0),
+		("app error", ExitReason::Error(&app), 2),
+		("protocol error", ExitReason::Error(&protocol), EXIT_PROTOCOL),
+		("timeout", ExitReason::Error(&timeout), EXIT_TIMEOUT),
+		("signal", ExitReason::Signal(libc::SIGTERM), 128 + libc::SIGTERM),
+	];
+	for (i, (name, reason, code)) in scenarios.iter().enumerate() {
+		assert_eq!(exit_code(reason), *code, "{}", name);
+		for (other, _, other_code) in &scenarios[..i] {
+			assert_ne!(code, other_code, "{} and {} exit alike", name, other);
+		}
+	}
+	let io = OkcError::Io(std::io::ErrorKind::ConnectionReset.into());
+	assert_eq!(exit_code(&ExitReason::Error(&io)), 1);
+	assert_eq!(exit_code(&ExitReason::Child(Some(3))), 3);
+	assert_eq!(exit_code(&ExitReason::Child(Some(0))), 0);
+	assert_eq!(exit_code(&ExitReason::Child(None)), 1);
+	assert_eq!(exit_code(&ExitReason::Startup), 1);
+}
+
+#[test]
+fn log_specs() {
+	for spec in ["warn", "debug,okc_agents::gpg=trace", "okc_agents", "info,okc_agents=", "trace/connection"].iter() {
+		assert_eq!(check_log_spec(spec), Ok(()), "{}", spec);
+	}
+	for spec in ["okc_agents=verbose", "=loud", "a=b=c", "info/a/b"].iter() {
+		assert!(check_log_spec(spec).is_err(), "{}", spec);
+	}
+}
+
+#[test]
+fn flush_logs_waits_for_queued_records() {
+	// Slow enough that the record is still queued when the logs are flushed.
+	struct SlowDrain(Arc<Mutex<Vec<String>>>);
+	impl Drain for SlowDrain {
+		type Ok = ();
+		type Err = slog::Never;
+		fn log(&self, record: &Record, _: &OwnedKVList) -> Result<(), slog::Never> {
+			std::thread::sleep(Duration::from_millis(100));
+			self.0.lock().unwrap().push(format!("{} {}", record.level().as_short_str(), record.msg()));
+			Ok(())
+		}
+	}
+	let records = Arc::new(Mutex::new(Vec::new()));
+	let (drain, guard) = Async::new(SlowDrain(records.clone())).build_with_guard();
+	*LOG_GUARD.lock().unwrap() = Some(guard);
+	let logger = Logger::root(drain.ignore_res(), o!());
+	error!(logger, "invalid OKC_CHUNK_SIZE");
+	flush_logs();
+	assert_eq!(*records.lock().unwrap(), ["ERRO invalid OKC_CHUNK_SIZE"]);
+	assert!(LOG_GUARD.lock().unwrap().is_none());
+}
+
+#[test]
+fn error_is_logged_before_exit() {
+	// The logs go through an asynchronous drain, whatever is still queued when the process exits is lost.
+	let output = std::process::Command::new(env!("CARGO_BIN_EXE_okc-gpg"))
+		.env("RUST_LOG", "debug")
+		.env("OKC_CHUNK_SIZE", "bogus")
+		.stdin(std::process::Stdio::null())
+		.output().unwrap();
+	assert_eq!(output.status.code(), Some(1));
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	let last = stderr.lines().last().unwrap_or_default();
+	assert!(last.contains("ERRO") && last.contains("OKC_CHUNK_SIZE"), "{}", stderr);
+}